@@ -2,20 +2,85 @@ mod network;
 use std::sync::Arc;
 use std::time::Duration;
 
-use network::{Client, MultiplexManager};
+use network::{Client, ClientConfig, MultiplexManager};
+use shared::{
+    handshake::PreSharedKey,
+    identity::{IdentityKeypair, PublicIdentity},
+};
+
+/// Secret key identifying this agent, generated on first start
+const IDENTITY_KEY_PATH: &str = "agent_identity.key";
+/// Public key to add to the server trust store
+const IDENTITY_PUBLIC_KEY_PATH: &str = "agent_identity.pub";
+/// Server public key to pin, as written by the server on first start
+const SERVER_IDENTITY_PATH: &str = "server_identity.pub";
+/// Optional pre-shared key, requests the pre-shared key handshake mode when present
+const PRE_SHARED_KEY_PATH: &str = "pre_shared.key";
+/// Delay before the first reconnection attempt, doubled after every attempt that fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Longest delay between attempts failing with an error retrying may fix, like an overloaded server
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Longest delay between attempts failing with an error only a configuration change fixes, like a
+/// rejected identity. The agent still retries, the error may come from an unauthenticated reject.
+const MAX_PERMANENT_RECONNECT_DELAY: Duration = Duration::from_secs(60 * 60);
 
 fn main() {
-    let client = loop {
-        match Client::new("127.0.0.1:1337") {
-            Ok(client) => break client,
-            Err(_) => {
-                eprintln!("Failed to connect to server, retrying in 5 seconds..");
-                std::thread::sleep(Duration::from_secs(5));
+    let identity =
+        IdentityKeypair::load_or_generate(IDENTITY_KEY_PATH).expect("Failed to load agent identity");
+    identity
+        .public_key()
+        .save(IDENTITY_PUBLIC_KEY_PATH)
+        .expect("Failed to write agent public identity");
+    println!("Agent identity: {}", identity.public_key());
+
+    let mut config = ClientConfig::new(identity);
+    if std::path::Path::new(PRE_SHARED_KEY_PATH).exists() {
+        config.pre_shared_key =
+            Some(PreSharedKey::load(PRE_SHARED_KEY_PATH).expect("Failed to load pre-shared key"));
+    } else {
+        // Without it any server key would be accepted
+        config.server_identity = Some(
+            PublicIdentity::load(SERVER_IDENTITY_PATH)
+                .expect("Failed to load pinned server identity, copy the server's server_identity.pub next to the agent"),
+        );
+    }
+
+    // Tickets issued by the server are kept in the config, reconnections resume the session
+    loop {
+        let client = connect(&config);
+        if client.resumed() {
+            println!("Resumed session with server (protocol v{})", client.version());
+        } else {
+            println!("Connected to server successfully! (protocol v{})", client.version());
+        }
+
+        run(client);
+        eprintln!("Connection to server lost, reconnecting in {:?}..", RECONNECT_DELAY);
+        std::thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+fn connect(config: &ClientConfig) -> Client {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        match Client::new("127.0.0.1:1337", config) {
+            Ok(client) => return client,
+            Err(e) => {
+                let max_delay = match e.is_retryable() {
+                    true => MAX_RECONNECT_DELAY,
+                    false => MAX_PERMANENT_RECONNECT_DELAY,
+                };
+                delay = delay.min(max_delay);
+                eprintln!("Failed to connect to server: {e}, retrying in {delay:?}..");
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(max_delay);
             }
         }
-    };
-    println!("Connected to server successfully!");
+    }
+}
 
+/// Echo every stream opened by the server until the connection is lost
+fn run(client: Client) {
     let manager = Arc::new(MultiplexManager::new(client));
 
     let _receive_thread = manager.start();
@@ -24,17 +89,13 @@ fn main() {
 
     loop {
         match manager.accept_stream() {
-            Ok(stream) => {
+            Ok(mut stream) => {
                 println!("Stream opened by server: stream_id={}", stream.id());
 
                 std::thread::spawn(move || {
                     loop {
                         match stream.receive() {
-                            Ok(data) => {
-                                if data.is_empty() {
-                                    println!("Stream {} closed", stream.id());
-                                    break;
-                                }
+                            Ok(Some(data)) => {
                                 println!(
                                     "Stream {}: received {} bytes: {:?}",
                                     stream.id(),
@@ -42,12 +103,17 @@ fn main() {
                                     String::from_utf8_lossy(&data)
                                 );
 
-                                if let Err(e) = stream.send_bytes(&data) {
+                                if let Err(e) = stream.send_bytes(data) {
                                     eprintln!("Failed to send on stream {}: {}", stream.id(), e);
                                     break;
                                 }
                                 println!("Stream {}: sent echo", stream.id());
                             }
+                            // Dropping the stream finishes our side too
+                            Ok(None) => {
+                                println!("Stream {} finished by server", stream.id());
+                                break;
+                            }
                             Err(e) => {
                                 eprintln!("Stream {} receive error: {}", stream.id(), e);
                                 break;
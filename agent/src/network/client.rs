@@ -1,32 +1,46 @@
-use std::io::Write;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use shared::encryption::{decrypt, encrypt};
+use shared::encryption::SessionCipher;
 use shared::error::NetworkError;
+use shared::handshake::Capabilities;
 
-use super::{Connection, ReadHalf, WriteHalf};
+use super::{ClientConfig, Connection, ReadHalf, SessionTickets, WriteHalf};
 
 #[derive(Debug)]
 pub struct Client {
     reader: ReadHalf,
     writer: Arc<Mutex<WriteHalf>>,
-    shared_secret: [u8; 32],
+    cipher: SessionCipher,
+    version: u16,
+    capabilities: Capabilities,
+    resumed: bool,
+    max_frame_size: u32,
+    heartbeat_interval: Duration,
+    session_tickets: SessionTickets,
 }
 
 impl Client {
-    pub fn new(addr: &str) -> Result<Self, NetworkError> {
+    pub fn new(addr: &str, config: &ClientConfig) -> Result<Self, NetworkError> {
         let connection = Connection::connect(addr)?;
         let (mut reader, mut writer) = connection.split();
 
-        let shared_secret = super::perform_handshake(&mut reader, &mut writer)?;
+        let outcome = super::perform_handshake(&mut reader, &mut writer, config)?;
 
         Ok(Client {
             reader,
             writer: Arc::new(Mutex::new(writer)),
-            shared_secret,
+            cipher: SessionCipher::client(outcome.keys, outcome.cipher_suite, config.rekey_policy),
+            version: outcome.version,
+            capabilities: outcome.capabilities,
+            resumed: outcome.resumed,
+            max_frame_size: outcome.max_frame_size,
+            heartbeat_interval: config.heartbeat_interval,
+            session_tickets: config.session_tickets.for_session(outcome.resumption_secret),
         })
     }
 
+    #[allow(dead_code)]
     pub fn shutdown(&self) -> Result<(), NetworkError> {
         let mut writer = match self.writer.lock() {
             Ok(writer) => writer,
@@ -42,52 +56,34 @@ impl Client {
         Ok(())
     }
 
-    pub fn send(&self, buf: &[u8]) -> Result<(), NetworkError> {
-        let (encrypted_buf, nonce) = encrypt(&self.shared_secret, buf)?;
-
-        let len = encrypted_buf.len() as u32;
-        let total_size = 4 + nonce.len() + encrypted_buf.len();
-        let mut data = vec![0u8; total_size];
-
-        data[0..4].copy_from_slice(&len.to_be_bytes());
-        data[4..16].copy_from_slice(&nonce);
-        data[16..].copy_from_slice(&encrypted_buf);
-
-        let mut writer = match self.writer.lock() {
-            Ok(writer) => writer,
-            Err(_) => {
-                return Err(NetworkError::LockError);
-            }
-        };
-
-        writer.write_all(&data)?;
-        writer.flush()?;
-        Ok(())
+    /// Protocol version negotiated with the server
+    pub fn version(&self) -> u16 {
+        self.version
     }
 
-    pub fn receive(&mut self) -> Result<Vec<u8>, NetworkError> {
-        let mut len_buf = [0u8; 4];
-        self.reader.read_exact(&mut len_buf)?;
-
-        let len = u32::from_be_bytes(len_buf) as usize;
-        if len == 0 {
-            return Ok(Vec::new());
-        }
-
-        let mut nonce_buf = vec![0u8; 12];
-        self.reader.read_exact(&mut nonce_buf)?;
+    /// Whether the handshake resumed a previous session with a ticket
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
 
-        let mut encrypted_buf = vec![0u8; len];
-        self.reader.read_exact(&mut encrypted_buf)?;
+    /// Largest frame either peer may send, negotiated with the server
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
 
-        let decrypted_data = decrypt(&self.shared_secret, &nonce_buf, &encrypted_buf)?;
+    /// How often the multiplex manager sends a Heartbeat
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_interval
+    }
 
-        Ok(decrypted_data)
+    /// Capabilities negotiated with the server
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
     }
 
-    /// Deconstruct the client into its components (reader, writer, shared_secret)
+    /// Deconstruct the client into its components (reader, writer, cipher, session tickets)
     /// This is useful for the multiplex manager to avoid mutex contention
-    pub fn into_parts(self) -> (ReadHalf, Arc<Mutex<WriteHalf>>, [u8; 32]) {
-        (self.reader, self.writer, self.shared_secret)
+    pub fn into_parts(self) -> (ReadHalf, Arc<Mutex<WriteHalf>>, SessionCipher, SessionTickets) {
+        (self.reader, self.writer, self.cipher, self.session_tickets)
     }
 }
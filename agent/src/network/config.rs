@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use shared::{
+    encryption::{CipherSuite, RekeyPolicy},
+    handshake::{Capabilities, PreSharedKey},
+    framing::DEFAULT_MAX_FRAME_SIZE,
+    identity::{IdentityKeypair, PublicIdentity},
+};
+
+use super::TicketCache;
+
+pub struct ClientConfig {
+    /// Long-term key proving the agent identity to the server
+    pub identity: IdentityKeypair,
+    /// Expected server identity, the handshake fails if the server presents another key.
+    /// Required in identity mode, only the pre-shared key mode connects without it.
+    pub server_identity: Option<PublicIdentity>,
+    /// Requests the pre-shared key handshake mode when set
+    pub pre_shared_key: Option<PreSharedKey>,
+    /// When the sending key is automatically rotated
+    pub rekey_policy: RekeyPolicy,
+    /// Cipher suites offered to the server, defaults to the ones this machine accelerates
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Optional features offered to the server
+    pub capabilities: Capabilities,
+    /// Features the handshake fails without, like `HYBRID_KEM` to always get post-quantum keys
+    pub required_capabilities: Capabilities,
+    /// Session ticket of the last connection, shared with the connections made with this config
+    pub session_tickets: TicketCache,
+    /// Largest frame accepted from the server, the server may lower it during the handshake
+    pub max_frame_size: u32,
+    /// How often a Heartbeat is sent, must stay below the server idle timeout of 5 minutes
+    pub heartbeat_interval: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(identity: IdentityKeypair) -> Self {
+        ClientConfig {
+            identity,
+            server_identity: None,
+            pre_shared_key: None,
+            rekey_policy: RekeyPolicy::default(),
+            cipher_suites: CipherSuite::local_preference(),
+            capabilities: Capabilities::DEFAULT,
+            required_capabilities: Capabilities::empty(),
+            session_tickets: TicketCache::new(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            heartbeat_interval: Duration::from_secs(60),
+        }
+    }
+}
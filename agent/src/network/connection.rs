@@ -26,6 +26,8 @@ impl Connection {
 
     pub fn connect<A: std::net::ToSocketAddrs>(addr: A) -> io::Result<Self> {
         let stream = TcpStream::connect(addr)?;
+        // Packets are written a flush at a time, Nagle would only delay small control packets
+        stream.set_nodelay(true)?;
         Self::new(stream)
     }
 
@@ -45,10 +47,6 @@ impl ReadHalf {
         self.stream.read(buf)
     }
 
-    pub fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
-        self.stream.read_exact(buf)
-    }
-
     #[allow(dead_code)]
     pub fn shutdown(&self) -> io::Result<()> {
         self.stream.shutdown(std::net::Shutdown::Read)
@@ -64,6 +62,7 @@ impl WriteHalf {
         self.stream.flush()
     }
 
+    #[allow(dead_code)]
     pub fn shutdown(&self) -> io::Result<()> {
         self.stream.shutdown(std::net::Shutdown::Write)
     }
@@ -1,27 +1,79 @@
-use aes_gcm::aead::OsRng;
 use shared::{
-    encryption::decrypt,
+    encryption::{CipherSuite, KeySchedule, SecretKey, SessionKeys, decrypt, encrypt},
     error::NetworkError,
-    packets::{EncryptionRequest, EncryptionResponse, Packet, Packets, from_packet_bytes},
+    framing::{MAX_FRAME_SIZE, MIN_FRAME_SIZE},
+    handshake::{
+        Capabilities, HandshakeMode, LENGTH_PREFIX_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        Transcript, encode_handshake_packet, handshake_packet_length,
+    },
+    identity::{PublicIdentity, SIGNATURE_SIZE},
+    key_exchange::AgentKeyShare,
+    packets::{
+        AgentAuthentication, EncryptionRequest, EncryptionResponse, Packet, Packets,
+        from_packet_bytes,
+    },
 };
-use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::ClientConfig;
+
+/// Result of a successful handshake
+pub struct HandshakeOutcome {
+    pub keys: SessionKeys,
+    pub cipher_suite: CipherSuite,
+    pub version: u16,
+    pub capabilities: Capabilities,
+    /// Whether the server accepted our session ticket
+    pub resumed: bool,
+    /// Secret of the ticket the server issues for this session
+    pub resumption_secret: SecretKey,
+    /// Largest frame either peer may send
+    pub max_frame_size: u32,
+}
 
 pub fn perform_handshake(
     reader: &mut impl std::io::Read,
     writer: &mut impl std::io::Write,
-) -> Result<[u8; 32], NetworkError> {
-    let private_key = EphemeralSecret::random_from_rng(OsRng);
-    let public_key = PublicKey::from(&private_key);
+    config: &ClientConfig,
+) -> Result<HandshakeOutcome, NetworkError> {
+    let key_share = AgentKeyShare::generate(config.capabilities.contains(Capabilities::HYBRID_KEM));
     let verify_token: u64 = rand::random();
+    let mode = match config.pre_shared_key {
+        Some(_) => HandshakeMode::PreSharedKey,
+        None => HandshakeMode::Identity,
+    };
+    // Only the pre-shared key authenticates the server without a pinned key, any server would
+    // pass the signature check otherwise
+    if mode == HandshakeMode::Identity && config.server_identity.is_none() {
+        return Err(NetworkError::ServerAuthenticationFailed(
+            "no pinned server identity to check the server against".to_string(),
+        ));
+    }
+    let cached_ticket = config.session_tickets.get();
+    let max_frame_size = config.max_frame_size.clamp(MIN_FRAME_SIZE, MAX_FRAME_SIZE);
 
-    let packet = EncryptionRequest::new(public_key.to_bytes(), verify_token);
+    let packet = EncryptionRequest::new(
+        config.capabilities,
+        key_share.public_key(),
+        verify_token,
+        config.identity.public_key().to_bytes(),
+        mode,
+        CipherSuite::to_mask(&config.cipher_suites),
+        key_share.kem_key(),
+        cached_ticket.as_ref().map(|cached| cached.ticket.clone()),
+        max_frame_size,
+    );
     let serialized_packet = packet.serialize()?;
-    writer.write_all(&serialized_packet)?;
+    write_handshake_packet(writer, &serialized_packet)?;
 
-    let mut response_buffer = vec![0; EncryptionResponse::PACKET_SIZE];
-    reader.read_exact(&mut response_buffer)?;
+    let response_buffer = read_handshake_packet(reader)?;
     let response = match from_packet_bytes(&response_buffer) {
         Ok(Packets::EncryptionResponse(packet)) => packet,
+        Ok(Packets::HandshakeReject(reject)) => {
+            return Err(NetworkError::HandshakeRejected {
+                code: reject.code,
+                message: reject.message,
+            });
+        }
         Ok(_) => {
             return Err(NetworkError::UnexpectedPacket);
         }
@@ -30,11 +82,39 @@ pub fn perform_handshake(
         }
     };
 
-    let shared_secret = private_key
-        .diffie_hellman(&PublicKey::from(response.key))
-        .to_bytes();
+    // Nothing the server chose is acted on before the response is authenticated, by its signature
+    // or, when resuming, by the resumption secret of our ticket. Without a ticket a resumption
+    // can't be trusted.
+    let resumption_key = match (response.resumed, &cached_ticket) {
+        (true, Some(cached)) => Some(&cached.resumption_secret),
+        (true, None) => return Err(NetworkError::UnexpectedPacket),
+        (false, _) => None,
+    };
+
+    let mut transcript = verify_server_identity(&serialized_packet, &response, config)?;
 
-    let decrypted_token_bytes = decrypt(&shared_secret, &response.nonce, &response.verify_token)?;
+    let shared_secret = key_share.finish(response.key, response.kem_ciphertext.as_deref())?;
+    let key_schedule = KeySchedule::new(
+        &shared_secret,
+        resumption_key.or(config.pre_shared_key.as_ref().map(|key| key.secret())),
+    );
+    let handshake_key = key_schedule.handshake_key(&transcript.hash());
+
+    // A server without our pre-shared key derives other keys and can't have encrypted the token,
+    // it answers in the mode it speaks so we can tell why
+    let decrypted_token_bytes = decrypt(&handshake_key, &response.nonce, &response.verify_token)
+        .map_err(|e| {
+            if response.mode != mode {
+                NetworkError::HandshakeModeMismatch {
+                    requested: mode,
+                    got: response.mode,
+                }
+            } else if mode == HandshakeMode::PreSharedKey && !response.resumed {
+                NetworkError::PreSharedKeyMismatch
+            } else {
+                NetworkError::CryptError(e)
+            }
+        })?;
     let decrypted_token = u64::from_be_bytes(
         decrypted_token_bytes
             .try_into()
@@ -48,5 +128,225 @@ pub fn perform_handshake(
         });
     }
 
-    Ok(shared_secret)
+    let supported_versions = MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION;
+    if !supported_versions.contains(&response.version) {
+        return Err(NetworkError::UnsupportedProtocolVersion {
+            offered: supported_versions,
+            supported: response.version..=response.version,
+        });
+    }
+
+    // The server may only enable features we asked for
+    if !config.capabilities.contains(response.capabilities) {
+        return Err(NetworkError::UnexpectedPacket);
+    }
+    let missing = config.required_capabilities.difference(response.capabilities);
+    if missing != Capabilities::empty() {
+        return Err(NetworkError::CapabilityNotNegotiated(missing));
+    }
+    if shared_secret.is_hybrid() != response.capabilities.contains(Capabilities::HYBRID_KEM) {
+        return Err(NetworkError::InvalidKeyShare(
+            "ML-KEM ciphertext doesn't match the negotiated capabilities".to_string(),
+        ));
+    }
+
+    if response.mode != mode {
+        return Err(NetworkError::HandshakeModeMismatch {
+            requested: mode,
+            got: response.mode,
+        });
+    }
+
+    // The server may only lower the maximum frame size we offered
+    if !(MIN_FRAME_SIZE..=max_frame_size).contains(&response.max_frame_size) {
+        return Err(NetworkError::InvalidFrame(format!(
+            "server chose a maximum frame size of {} bytes, we offered {}",
+            response.max_frame_size, max_frame_size
+        )));
+    }
+
+    // The server falls back to its own preference when nothing matches, don't follow it
+    if !config.cipher_suites.contains(&response.cipher_suite) {
+        return Err(NetworkError::NoCommonCipherSuite {
+            offered: config.cipher_suites.clone(),
+            got: response.cipher_suite,
+        });
+    }
+
+    // Prove we own the identity key announced in the request, and derived the same keys
+    transcript.update(&response.signature);
+    let transcript_hash = transcript.hash();
+    let (verify_data, nonce) = encrypt(&handshake_key, &transcript_hash)?;
+    let signature = match response.resumed {
+        true => [0u8; SIGNATURE_SIZE],
+        false => config.identity.sign(&transcript_hash),
+    };
+    let authentication = AgentAuthentication::new(
+        signature,
+        nonce.try_into().map_err(|_| NetworkError::ConvertError)?,
+        verify_data.try_into().map_err(|_| NetworkError::ConvertError)?,
+    );
+    write_handshake_packet(writer, &authentication.serialize()?)?;
+
+    Ok(HandshakeOutcome {
+        keys: key_schedule.session_keys(&transcript_hash),
+        cipher_suite: response.cipher_suite,
+        version: response.version,
+        capabilities: response.capabilities,
+        resumed: response.resumed,
+        resumption_secret: key_schedule.resumption_secret(&transcript_hash),
+        max_frame_size: response.max_frame_size,
+    })
+}
+
+fn verify_server_identity(
+    request: &[u8],
+    response: &EncryptionResponse,
+    config: &ClientConfig,
+) -> Result<Transcript, NetworkError> {
+    let identity = PublicIdentity::from_bytes(response.identity_key);
+    if let Some(expected) = &config.server_identity
+        && *expected != identity
+    {
+        return Err(NetworkError::ServerAuthenticationFailed(format!(
+            "server presented key {identity}, expected {expected}"
+        )));
+    }
+
+    let transcript = Transcript::server_signed(request, response);
+    if !response.resumed {
+        identity
+            .verify(&transcript.hash(), &response.signature)
+            .map_err(|e| NetworkError::ServerAuthenticationFailed(e.to_string()))?;
+    }
+
+    Ok(transcript)
+}
+
+fn read_handshake_packet(reader: &mut impl std::io::Read) -> Result<Vec<u8>, NetworkError> {
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    reader.read_exact(&mut prefix)?;
+
+    let mut packet = vec![0u8; handshake_packet_length(prefix)?];
+    reader.read_exact(&mut packet)?;
+    Ok(packet)
+}
+
+fn write_handshake_packet(writer: &mut impl std::io::Write, packet: &[u8]) -> Result<(), NetworkError> {
+    writer.write_all(&encode_handshake_packet(packet))?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use shared::{
+        handshake::RejectCode,
+        identity::IdentityKeypair,
+        packets::HandshakeReject,
+    };
+
+    use super::*;
+
+    /// Run the handshake against a server pinned as `server` answering `response` whatever the
+    /// request
+    fn handshake_with_response(
+        response: &[u8],
+        server: PublicIdentity,
+    ) -> Result<HandshakeOutcome, NetworkError> {
+        let mut config = ClientConfig::new(IdentityKeypair::generate());
+        config.server_identity = Some(server);
+        let mut reader = Cursor::new(encode_handshake_packet(response));
+        perform_handshake(&mut reader, &mut Vec::new(), &config)
+    }
+
+    #[test]
+    fn reports_handshake_reject() {
+        let reject = HandshakeReject::new(RejectCode::Overloaded, "too many connections");
+
+        let server = IdentityKeypair::generate().public_key();
+        let error = handshake_with_response(&reject.serialize().unwrap(), server).err().unwrap();
+
+        assert!(matches!(
+            error,
+            NetworkError::HandshakeRejected { code: RejectCode::Overloaded, .. }
+        ));
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn decodes_unknown_reject_codes() {
+        let reject = HandshakeReject::new(RejectCode::Unknown(42), "from a newer server");
+
+        let server = IdentityKeypair::generate().public_key();
+        let error = handshake_with_response(&reject.serialize().unwrap(), server).err().unwrap();
+
+        assert!(matches!(
+            error,
+            NetworkError::HandshakeRejected { code: RejectCode::Unknown(42), .. }
+        ));
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn authenticates_response_before_negotiated_fields() {
+        // Every negotiated field is invalid, the missing signature must be noticed first
+        let server = IdentityKeypair::generate().public_key();
+        let response = EncryptionResponse::new(
+            PROTOCOL_VERSION + 1,
+            Capabilities::SUPPORTED,
+            [1; 32],
+            [0; 12],
+            [0; 24],
+            server.to_bytes(),
+            [0; SIGNATURE_SIZE],
+            HandshakeMode::PreSharedKey,
+            CipherSuite::ChaCha20Poly1305,
+            None,
+            false,
+            MAX_FRAME_SIZE * 2,
+        );
+
+        let error = handshake_with_response(&response.serialize().unwrap(), server).err().unwrap();
+
+        assert!(matches!(error, NetworkError::ServerAuthenticationFailed(_)));
+    }
+
+    #[test]
+    fn refuses_resumption_without_ticket() {
+        let server = IdentityKeypair::generate().public_key();
+        let response = EncryptionResponse::new(
+            PROTOCOL_VERSION,
+            Capabilities::DEFAULT,
+            [1; 32],
+            [0; 12],
+            [0; 24],
+            server.to_bytes(),
+            [0; SIGNATURE_SIZE],
+            HandshakeMode::Identity,
+            CipherSuite::Aes256Gcm,
+            None,
+            true,
+            MIN_FRAME_SIZE,
+        );
+
+        let error = handshake_with_response(&response.serialize().unwrap(), server).err().unwrap();
+
+        assert!(matches!(error, NetworkError::UnexpectedPacket));
+    }
+
+    #[test]
+    fn refuses_identity_mode_without_pinned_server() {
+        let config = ClientConfig::new(IdentityKeypair::generate());
+        let mut sent = Vec::new();
+
+        let error = perform_handshake(&mut Cursor::new(Vec::new()), &mut sent, &config)
+            .err()
+            .unwrap();
+
+        assert!(matches!(error, NetworkError::ServerAuthenticationFailed(_)));
+        assert!(sent.is_empty());
+    }
 }
@@ -1,10 +1,14 @@
 mod client;
+mod config;
 mod connection;
 mod handshake;
 mod stream;
 mod multiplex;
+mod ticket_cache;
 
 pub use client::Client;
+pub use config::ClientConfig;
 pub use multiplex::MultiplexManager;
+pub use ticket_cache::{SessionTickets, TicketCache};
 pub(crate) use connection::{Connection, ReadHalf, WriteHalf};
 use handshake::perform_handshake;
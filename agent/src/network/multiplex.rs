@@ -1,27 +1,44 @@
 use std::collections::HashMap;
 use std::io::Write;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use bytes::{Bytes, BytesMut};
 use crossbeam::channel;
 
 use shared::{
     error::NetworkError,
-    multiplexing::{MIN_DATA_STREAM_ID, StreamId},
-    packets::{Packet, Packets, StreamClose, StreamData, StreamOpen, from_packet_bytes},
+    framing::{FrameCodec, FrameDecoder, FrameEncoder},
+    multiplexing::{Event, Multiplexer, READ_BUFFER_SIZE, ResetCode, Role, StreamId},
 };
 
-use super::{client::Client, stream::Stream, ReadHalf, WriteHalf};
-use shared::encryption::{encrypt, decrypt};
+use super::{client::Client, stream::{Incoming, Stream}, ReadHalf, SessionTickets, WriteHalf};
 
+/// Drives the `Multiplexer` of the connection with blocking IO
 pub struct MultiplexManager {
     reader: Mutex<ReadHalf>,
+    decoder: Mutex<FrameDecoder>,
+    // Packets are popped from the multiplexer and encrypted while holding the writer, so they
+    // are written in sequence order
     writer: Arc<Mutex<WriteHalf>>,
-    shared_secret: [u8; 32],
-    streams: Arc<Mutex<HashMap<StreamId, channel::Sender<Vec<u8>>>>>,
-    next_id: AtomicU32,
-    incoming_streams_tx: channel::Sender<Stream>,
+    encoder: Mutex<FrameEncoder>,
+    // Frames are encoded into it then written at once, reused to avoid an allocation per flush
+    write_buffer: Mutex<BytesMut>,
+    multiplexer: Mutex<Multiplexer>,
+    // Wakes up senders waiting for the server to grant more credit, or for the connection to
+    // close, and the heartbeat thread once it closes
+    window_updated: Condvar,
+    // Set with the multiplexer held, so a sender can't miss it between its check and its wait
+    closed: AtomicBool,
+    session_tickets: SessionTickets,
+    heartbeat_interval: Duration,
+    // Unbounded, the receive windows bound what the server can send before streams are read.
+    // Its sender is dropped once the server finished sending or reset the stream.
+    streams: Arc<Mutex<HashMap<StreamId, channel::Sender<Incoming>>>>,
+    // Dropped once the receive loop ends, so `accept_stream` notices the connection is gone
+    incoming_streams_tx: Mutex<Option<channel::Sender<Stream>>>,
     incoming_streams_rx: Arc<Mutex<channel::Receiver<Stream>>>,
 }
 
@@ -29,43 +46,63 @@ impl MultiplexManager {
     pub fn new(client: Client) -> Self {
         let (incoming_tx, incoming_rx) = channel::unbounded();
 
-        let (reader, writer, shared_secret) = client.into_parts();
+        let capabilities = client.capabilities();
+        let max_frame_size = client.max_frame_size();
+        let heartbeat_interval = client.heartbeat_interval();
+        let (reader, writer, cipher, session_tickets) = client.into_parts();
+        let (encoder, decoder) = FrameCodec::new(cipher, capabilities, max_frame_size).into_split();
 
         Self {
             reader: Mutex::new(reader),
+            decoder: Mutex::new(decoder),
             writer,
-            shared_secret,
+            encoder: Mutex::new(encoder),
+            write_buffer: Mutex::new(BytesMut::with_capacity(READ_BUFFER_SIZE)),
+            multiplexer: Mutex::new(Multiplexer::new(Role::Agent, capabilities, max_frame_size)),
+            window_updated: Condvar::new(),
+            closed: AtomicBool::new(false),
+            session_tickets,
+            heartbeat_interval,
             streams: Arc::new(Mutex::new(HashMap::new())),
-            next_id: AtomicU32::new(MIN_DATA_STREAM_ID),
-            incoming_streams_tx: incoming_tx,
+            incoming_streams_tx: Mutex::new(Some(incoming_tx)),
             incoming_streams_rx: Arc::new(Mutex::new(incoming_rx)),
         }
     }
 
     pub fn start(self: &Arc<Self>) -> thread::JoinHandle<()> {
+        let heartbeat = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = heartbeat.heartbeat_loop() {
+                eprintln!("Failed to send heartbeat: {e}");
+            }
+        });
+
         let self_clone = self.clone();
         thread::spawn(move || {
             if let Err(e) = self_clone.receive_loop() {
                 eprintln!("Multiplex receive loop error: {e}");
             }
+            if let Ok(_multiplexer) = self_clone.multiplexer() {
+                self_clone.closed.store(true, Ordering::Release);
+                self_clone.window_updated.notify_all();
+            }
+            if let Ok(mut incoming_streams_tx) = self_clone.incoming_streams_tx.lock() {
+                incoming_streams_tx.take();
+            }
         })
     }
 
+    #[allow(dead_code)]
     pub fn open_stream(self: &Arc<Self>) -> Result<Stream, NetworkError> {
-        let stream_id = self.next_id.fetch_add(1, Ordering::SeqCst);
-
         let (stream_tx, stream_rx) = channel::unbounded();
 
-        {
+        let stream_id = {
             let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
-            if streams.contains_key(&stream_id) {
-                return Err(NetworkError::StreamAlreadyExists(stream_id));
-            }
+            let stream_id = self.multiplexer()?.open_stream()?;
             streams.insert(stream_id, stream_tx);
-        }
-
-        let open_packet = StreamOpen { stream_id };
-        self.send_packet(&open_packet.serialize()?)?;
+            stream_id
+        };
+        self.flush()?;
 
         Ok(Stream::new(stream_id, self.clone(), stream_rx))
     }
@@ -78,122 +115,236 @@ impl MultiplexManager {
         rx.recv().map_err(|_| NetworkError::ChannelReceiveError)
     }
 
-    pub fn send_on_stream(&self, stream_id: StreamId, data: Vec<u8>) -> Result<(), NetworkError> {
-        let packet = StreamData { stream_id, data };
-        self.send_packet(&packet.serialize()?)
-    }
-
-    pub fn close_stream(&self, stream_id: StreamId) -> Result<(), NetworkError> {
-        {
-            let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
-            streams.remove(&stream_id);
-        }
-        let close_packet = StreamClose { stream_id };
-        self.send_packet(&close_packet.serialize()?)
-    }
-
-    fn receive_loop(self: &Arc<Self>) -> Result<(), NetworkError> {
+    /// Send `data` on a stream, blocking until the server grants credit when its window is full
+    pub fn send_on_stream(&self, stream_id: StreamId, mut data: Bytes) -> Result<(), NetworkError> {
         loop {
-            let data = self.receive_packet()?;
-
-            let packet = from_packet_bytes(&data)?;
+            self.multiplexer()?.send(stream_id, &mut data)?;
+            self.flush()?;
+            if data.is_empty() {
+                return Ok(());
+            }
 
-            println!("");
-            match packet {
-                Packets::StreamOpen(open) => {
-                    self.handle_stream_open(open)?;
-                }
-                Packets::StreamClose(close) => {
-                    self.handle_stream_close(close)?;
-                }
-                Packets::StreamData(data_packet) => {
-                    self.handle_stream_data(data_packet)?;
-                }
-                Packets::StreamError(error) => {
-                    eprintln!("Stream {} error: {}", error.stream_id, error.error);
-                }
-                _ => {
-                    eprintln!("Unexpected packet in multiplex receive loop");
+            let mut multiplexer = self.multiplexer()?;
+            while multiplexer.send_capacity(stream_id)? == 0 {
+                if self.closed.load(Ordering::Acquire) {
+                    return Err(NetworkError::StreamClosed(stream_id));
                 }
+                multiplexer = self
+                    .window_updated
+                    .wait(multiplexer)
+                    .map_err(|_| NetworkError::LockError)?;
             }
         }
     }
 
-    fn handle_stream_open(self: &Arc<Self>, open: StreamOpen) -> Result<(), NetworkError> {
-        let stream_id = open.stream_id;
+    /// Give back the credit of data read from a stream
+    pub fn release(&self, stream_id: StreamId, length: usize) -> Result<(), NetworkError> {
+        self.multiplexer()?.release(stream_id, length);
+        self.flush()
+    }
 
-        let (tx, rx) = channel::unbounded();
+    /// Finish sending on a stream, the server can still send until it finishes too
+    pub fn finish_stream(&self, stream_id: StreamId) -> Result<(), NetworkError> {
+        self.multiplexer()?.finish(stream_id)?;
+        self.flush()
+    }
 
+    /// Stop using a stream in both directions, see `Multiplexer::close_stream`
+    pub fn close_stream(&self, stream_id: StreamId) -> Result<(), NetworkError> {
         {
             let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
-            if streams.contains_key(&stream_id) {
-                return Err(NetworkError::StreamAlreadyExists(stream_id));
-            }
-            streams.insert(stream_id, tx.clone());
+            streams.remove(&stream_id);
         }
+        self.multiplexer()?.close_stream(stream_id)?;
+        self.flush()
+    }
 
-        let stream = Stream::new(stream_id, self.clone(), rx);
-        self.incoming_streams_tx
-            .send(stream)
-            .map_err(|_| NetworkError::ChannelSendError)?;
-        Ok(())
+    /// End both directions of a stream at once, telling the server why with `code`
+    pub fn reset_stream(&self, stream_id: StreamId, code: ResetCode) -> Result<(), NetworkError> {
+        {
+            let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
+            streams.remove(&stream_id);
+        }
+        self.multiplexer()?.reset(stream_id, code)?;
+        self.flush()
     }
 
-    fn handle_stream_close(&self, close: StreamClose) -> Result<(), NetworkError> {
-        let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
-        streams.remove(&close.stream_id);
-        Ok(())
+    /// Switch our sending key to its next generation, and ask the server to do the same
+    #[allow(dead_code)]
+    pub fn rekey(&self) -> Result<(), NetworkError> {
+        self.multiplexer()?.rekey()?;
+        self.flush()
     }
 
-    fn handle_stream_data(&self, data: StreamData) -> Result<(), NetworkError> {
-        let streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
+    /// Send a Heartbeat every `heartbeat_interval` until the connection closes, so the server
+    /// doesn't drop it as idle while no stream has traffic
+    fn heartbeat_loop(&self) -> Result<(), NetworkError> {
+        let mut next = Instant::now() + self.heartbeat_interval;
+        let mut multiplexer = self.multiplexer()?;
+        while !self.closed.load(Ordering::Acquire) {
+            let now = Instant::now();
+            if now < next {
+                // Window updates wake it up early too
+                multiplexer = self
+                    .window_updated
+                    .wait_timeout(multiplexer, next - now)
+                    .map_err(|_| NetworkError::LockError)?
+                    .0;
+                continue;
+            }
 
-        if let Some(tx) = streams.get(&data.stream_id) {
-            tx.send(data.data)
-                .map_err(|_| NetworkError::ChannelSendError)?;
-        } else {
-            eprintln!("Received data for unknown stream: {}", data.stream_id);
+            multiplexer.heartbeat();
+            drop(multiplexer);
+            self.flush()?;
+            next = Instant::now() + self.heartbeat_interval;
+            multiplexer = self.multiplexer()?;
         }
-
         Ok(())
     }
 
-    fn send_packet(&self, buf: &[u8]) -> Result<(), NetworkError> {
-        let (encrypted_buf, nonce) = encrypt(&self.shared_secret, buf)?;
+    fn receive_loop(self: &Arc<Self>) -> Result<(), NetworkError> {
+        let mut reader = self.reader.lock().map_err(|_| NetworkError::LockError)?;
+        let mut decoder = self.decoder.lock().map_err(|_| NetworkError::LockError)?;
+        let mut received = BytesMut::with_capacity(READ_BUFFER_SIZE);
 
-        let len = encrypted_buf.len() as u32;
-        let total_size = 4 + nonce.len() + encrypted_buf.len();
-        let mut data = vec![0u8; total_size];
+        loop {
+            // Read straight into the buffer packets are decrypted in
+            let buffered = received.len();
+            received.resize(buffered + READ_BUFFER_SIZE, 0);
+            let length = reader.read(&mut received[buffered..])?;
+            received.truncate(buffered + length);
+            if length == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
 
-        data[0..4].copy_from_slice(&len.to_be_bytes());
-        data[4..16].copy_from_slice(&nonce);
-        data[16..].copy_from_slice(&encrypted_buf);
+            let events = {
+                let mut multiplexer = self.multiplexer()?;
+                while let Some(packet) = decoder.decode_frame(&mut received)? {
+                    multiplexer.receive(packet)?;
+                }
+                std::iter::from_fn(|| multiplexer.poll_event()).collect::<Vec<_>>()
+            };
 
-        let mut writer = self.writer.lock().map_err(|_| NetworkError::LockError)?;
-        writer.write_all(&data)?;
-        writer.flush()?;
-        Ok(())
+            for event in events {
+                self.handle_event(event)?;
+            }
+            // Answers to key update requests
+            self.flush()?;
+        }
     }
 
-    fn receive_packet(&self) -> Result<Vec<u8>, NetworkError> {
-        let mut reader = self.reader.lock().map_err(|_| NetworkError::LockError)?;
-
-        let mut len_buf = [0u8; 4];
-        reader.read_exact(&mut len_buf)?;
+    fn handle_event(self: &Arc<Self>, event: Event) -> Result<(), NetworkError> {
+        match event {
+            Event::StreamOpened(stream_id) => {
+                let (tx, rx) = channel::unbounded();
+                {
+                    let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
+                    streams.insert(stream_id, tx);
+                }
 
-        let len = u32::from_be_bytes(len_buf) as usize;
-        if len == 0 {
-            return Ok(Vec::new());
+                let stream = Stream::new(stream_id, self.clone(), rx);
+                let refused = match &*self
+                    .incoming_streams_tx
+                    .lock()
+                    .map_err(|_| NetworkError::LockError)?
+                {
+                    Some(tx) => tx.send(stream).err().map(|e| e.0),
+                    None => Some(stream),
+                };
+                // Nobody accepts streams anymore, the connection still serves the others
+                if let Some(refused) = refused {
+                    self.streams
+                        .lock()
+                        .map_err(|_| NetworkError::LockError)?
+                        .remove(&stream_id);
+                    self.multiplexer()?.reset(stream_id, ResetCode::Refused)?;
+                    // Only dropped once reset, so it doesn't finish the stream first
+                    drop(refused);
+                }
+            }
+            Event::StreamData { stream_id, data } => {
+                let streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
+                let length = data.len();
+                let queued = streams
+                    .get(&stream_id)
+                    .is_some_and(|tx| tx.send(Ok(Some(data))).is_ok());
+                // Nobody will read it, so its credit is given back right away
+                if !queued {
+                    self.multiplexer()?.release(stream_id, length);
+                }
+            }
+            Event::StreamFinished(stream_id) => {
+                let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
+                // Its stream reads what is left, then the end of the stream
+                if let Some(tx) = streams.remove(&stream_id) {
+                    let _ = tx.send(Ok(None));
+                }
+            }
+            Event::StreamReset { stream_id, code } => {
+                let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
+                // Its stream reads what was queued before, then the reset
+                if let Some(tx) = streams.remove(&stream_id) {
+                    let _ = tx.send(Err(code));
+                }
+                // Senders waiting for credit on it fail
+                self.window_updated.notify_all();
+            }
+            Event::StreamFailed { stream_id, code, error } => {
+                eprintln!("Reset stream {stream_id}: {error}");
+                let mut streams = self.streams.lock().map_err(|_| NetworkError::LockError)?;
+                if let Some(tx) = streams.remove(&stream_id) {
+                    let _ = tx.send(Err(code));
+                }
+                self.window_updated.notify_all();
+            }
+            Event::WindowUpdated(_) => {
+                self.window_updated.notify_all();
+            }
+            Event::StreamError { stream_id, error } => {
+                eprintln!("Stream {stream_id} error: {error}");
+            }
+            Event::SessionTicket { lifetime, ticket } => {
+                self.session_tickets.store(ticket, lifetime);
+            }
+            Event::UnknownStream(stream_id) => {
+                eprintln!("Received data for unknown stream: {stream_id}");
+            }
+            Event::UnexpectedPacket => {
+                eprintln!("Unexpected packet in multiplex receive loop");
+            }
+            Event::PeerKeyUpdated { .. } => {}
         }
+        Ok(())
+    }
 
-        let mut nonce_buf = vec![0u8; 12];
-        reader.read_exact(&mut nonce_buf)?;
-
-        let mut encrypted_buf = vec![0u8; len];
-        reader.read_exact(&mut encrypted_buf)?;
+    /// Send the packets queued by the multiplexer. They are popped one at a time, those after
+    /// one that fails to encode stay queued for the next flush while the frames encoded before
+    /// it are still written, the server expects every sequence number.
+    fn flush(&self) -> Result<(), NetworkError> {
+        let mut writer = self.writer.lock().map_err(|_| NetworkError::LockError)?;
+        let mut encoder = self.encoder.lock().map_err(|_| NetworkError::LockError)?;
+        let mut frames = self.write_buffer.lock().map_err(|_| NetworkError::LockError)?;
+        frames.clear();
 
-        let decrypted_data = decrypt(&self.shared_secret, &nonce_buf, &encrypted_buf)?;
+        let mut result = Ok(());
+        loop {
+            // Popped before writing so the receive loop never waits on a blocked write
+            let Some(packet) = self.multiplexer()?.poll_transmit() else {
+                break;
+            };
+            result = encoder.encode_packet(&packet, &mut frames);
+            if result.is_err() {
+                break;
+            }
+        }
+        if !frames.is_empty() {
+            writer.write_all(&frames)?;
+            writer.flush()?;
+        }
+        result
+    }
 
-        Ok(decrypted_data)
+    fn multiplexer(&self) -> Result<MutexGuard<'_, Multiplexer>, NetworkError> {
+        self.multiplexer.lock().map_err(|_| NetworkError::LockError)
     }
 }
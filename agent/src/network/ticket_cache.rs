@@ -0,0 +1,80 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use shared::encryption::SecretKey;
+
+/// Latest session ticket received from the server, presented on the next connection to resume
+/// the session with an abbreviated handshake. Clones share the same ticket.
+#[derive(Clone, Default)]
+pub struct TicketCache {
+    ticket: Arc<Mutex<Option<CachedTicket>>>,
+}
+
+/// Stores the tickets received on one connection, along with the resumption secret of its session
+pub struct SessionTickets {
+    cache: TicketCache,
+    resumption_secret: SecretKey,
+}
+
+/// Ticket along with the resumption secret of the session that received it
+#[derive(Clone)]
+pub struct CachedTicket {
+    pub ticket: Vec<u8>,
+    pub resumption_secret: SecretKey,
+    expires_at: Instant,
+}
+
+impl TicketCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle storing the tickets of the session established with `resumption_secret`
+    pub fn for_session(&self, resumption_secret: SecretKey) -> SessionTickets {
+        SessionTickets {
+            cache: self.clone(),
+            resumption_secret,
+        }
+    }
+
+    /// Replace the cached ticket
+    pub fn store(&self, ticket: Vec<u8>, resumption_secret: SecretKey, lifetime: Duration) {
+        let mut cached = self.ticket.lock().unwrap_or_else(PoisonError::into_inner);
+        *cached = Some(CachedTicket {
+            ticket,
+            resumption_secret,
+            expires_at: Instant::now() + lifetime,
+        });
+    }
+
+    /// The cached ticket, if it didn't expire yet
+    pub fn get(&self) -> Option<CachedTicket> {
+        let mut cached = self.ticket.lock().unwrap_or_else(PoisonError::into_inner);
+        if cached.as_ref().is_some_and(|ticket| ticket.expires_at <= Instant::now()) {
+            *cached = None;
+        }
+        cached.clone()
+    }
+}
+
+impl SessionTickets {
+    /// Cache a ticket received from the server, replacing the previous one
+    pub fn store(&self, ticket: Vec<u8>, lifetime: Duration) {
+        self.cache.store(ticket, self.resumption_secret.clone(), lifetime);
+    }
+}
+
+impl fmt::Debug for TicketCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TicketCache")
+            .field("cached", &self.get().is_some())
+            .finish()
+    }
+}
+
+impl fmt::Debug for SessionTickets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionTickets").finish_non_exhaustive()
+    }
+}
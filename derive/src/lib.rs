@@ -39,14 +39,21 @@ pub fn derive_packet(input: TokenStream) -> TokenStream {
                 Ok(data)
             }
 
+            fn serialize_into(&self, dst: &mut bytes::BytesMut) -> Result<(), crate::packets::PacketError> {
+                bytes::BufMut::put_u8(dst, #packet_code);
+                let mut writer = bytes::BufMut::writer(&mut *dst);
+                match bincode::encode_into_std_write(self, &mut writer, bincode::config::standard()) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(crate::packets::PacketError::EncodingError(e.to_string()))
+                }
+            }
+
             fn deserialize(data: &[u8]) -> Result<Self, crate::packets::PacketError>
             where
                 Self: Sized,
             {
-                let (decoded, _) = match bincode::decode_from_slice(data, bincode::config::standard()) {
-                    Ok(res) => res,
-                    Err(e) => return Err(crate::packets::PacketError::DecodingError(e.to_string()))
-                };
+                // Bounded by the packet length so length prefixes can't trigger huge allocations
+                let (decoded, _) = crate::packets::decode_bounded(data)?;
                 Ok(decoded)
             }
 
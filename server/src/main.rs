@@ -1,12 +1,56 @@
-use tokio::net::TcpListener;
+use std::sync::Arc;
+
+use shared::{error::NetworkError, handshake::PreSharedKey, identity::IdentityKeypair};
+use tokio::{net::TcpListener, sync::Semaphore};
 use tracing::info;
 
 mod misc;
 mod network;
 
+/// Secret key the server signs handshakes with, generated on first start
+const IDENTITY_KEY_PATH: &str = "server_identity.key";
+/// Public key agents pin to authenticate the server
+const IDENTITY_PUBLIC_KEY_PATH: &str = "server_identity.pub";
+/// Identity keys of the agents allowed to connect
+const TRUST_STORE_PATH: &str = "trusted_agents.txt";
+/// Identity keys and addresses refused during the handshake, optional
+const BAN_LIST_PATH: &str = "banned_agents.txt";
+/// Optional pre-shared key, enables the pre-shared key handshake mode when present
+const PRE_SHARED_KEY_PATH: &str = "pre_shared.key";
+
 #[tokio::main]
 async fn main() {
     misc::start_logger();
+
+    let identity = IdentityKeypair::load_or_generate(IDENTITY_KEY_PATH)
+        .expect("Failed to load server identity");
+    identity
+        .public_key()
+        .save(IDENTITY_PUBLIC_KEY_PATH)
+        .expect("Failed to write server public identity");
+    info!("Server identity: {}", identity.public_key());
+
+    let trust_store =
+        misc::TrustStore::load_or_create(TRUST_STORE_PATH).expect("Failed to load trust store");
+    if trust_store.is_empty() {
+        tracing::warn!("No trusted agents in {}, every agent will be rejected", TRUST_STORE_PATH);
+    } else {
+        info!("Loaded {} trusted agents", trust_store.len());
+    }
+
+    let mut config = misc::ServerConfig::new(identity, trust_store);
+    if std::path::Path::new(BAN_LIST_PATH).exists() {
+        config.ban_list = misc::BanList::load(BAN_LIST_PATH).expect("Failed to load ban list");
+        info!("Loaded {} ban list entries", config.ban_list.len());
+    }
+    if std::path::Path::new(PRE_SHARED_KEY_PATH).exists() {
+        config.pre_shared_key =
+            Some(PreSharedKey::load(PRE_SHARED_KEY_PATH).expect("Failed to load pre-shared key"));
+        info!("Pre-shared key mode enabled");
+    }
+    let connection_slots = Arc::new(Semaphore::new(config.max_connections));
+    let config = Arc::new(config);
+
     let listener = TcpListener::bind("0.0.0.0:1337")
         .await
         .expect("Failed to bind to address");
@@ -17,11 +61,17 @@ async fn main() {
         match listener.accept().await {
             Ok((stream, addr)) => {
                 info!("Accepted connection from {}", addr);
+                let config = config.clone();
+                let connection_slots = connection_slots.clone();
                 tokio::spawn(async move {
-                    let res = misc::handle_connection(stream).await;
+                    let res = misc::handle_connection(stream, config, connection_slots).await;
 
-                    if let Err(e) = res {
-                        tracing::error!("Error in connection: {}", e);
+                    match res {
+                        Err(e @ NetworkError::HandshakeTimeout(_)) => {
+                            tracing::warn!("Dropping connection from {}: {}", addr, e);
+                        }
+                        Err(e) => tracing::error!("Error in connection: {}", e),
+                        Ok(()) => {}
                     }
                 });
             }
@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use shared::identity::{IdentityError, PublicIdentity};
+
+/// Agents refused during the handshake, even when they are in the trust store.
+///
+/// The file holds one hex encoded identity key or IP address per line, optionally followed by a note.
+/// Empty lines and lines starting with `#` are ignored:
+///
+/// ```text
+/// 3b6a27bcceb6a42d62a3a8d02a6f0d73653215771de243a63ac048a18b5c2c3f stolen laptop
+/// 203.0.113.7
+/// ```
+#[derive(Debug, Default)]
+pub struct BanList {
+    identities: HashSet<PublicIdentity>,
+    addresses: HashSet<IpAddr>,
+}
+
+impl BanList {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, IdentityError> {
+        let content = fs::read_to_string(path)?;
+        let mut ban_list = BanList::default();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let entry = line.split_whitespace().next().unwrap_or(line);
+            match entry.parse::<IpAddr>() {
+                Ok(address) => {
+                    ban_list.addresses.insert(address);
+                }
+                Err(_) => {
+                    ban_list.identities.insert(PublicIdentity::from_hex(entry)?);
+                }
+            }
+        }
+
+        Ok(ban_list)
+    }
+
+    pub fn is_identity_banned(&self, identity: &PublicIdentity) -> bool {
+        self.identities.contains(identity)
+    }
+
+    pub fn is_address_banned(&self, address: &IpAddr) -> bool {
+        self.addresses.contains(address)
+    }
+
+    pub fn len(&self) -> usize {
+        self.identities.len() + self.addresses.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::identity::IdentityKeypair;
+
+    fn load(content: &str) -> Result<BanList, IdentityError> {
+        let path = std::env::temp_dir().join(format!("banned_{}.txt", rand::random::<u64>()));
+        fs::write(&path, content).unwrap();
+        let ban_list = BanList::load(&path);
+        fs::remove_file(&path).unwrap();
+        ban_list
+    }
+
+    #[test]
+    fn parses_identities_addresses_and_comments() {
+        let banned = IdentityKeypair::generate().public_key();
+        let allowed = IdentityKeypair::generate().public_key();
+        let content =
+            format!("# banned\n\n{banned} stolen laptop\n 203.0.113.7 \n2001:db8::1 scanner\n");
+
+        let ban_list = load(&content).unwrap();
+
+        assert_eq!(ban_list.len(), 3);
+        assert!(ban_list.is_identity_banned(&banned));
+        assert!(!ban_list.is_identity_banned(&allowed));
+        assert!(ban_list.is_address_banned(&"203.0.113.7".parse().unwrap()));
+        assert!(ban_list.is_address_banned(&"2001:db8::1".parse().unwrap()));
+        assert!(!ban_list.is_address_banned(&"203.0.113.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        // Neither an address nor a key
+        assert!(matches!(load("203.0.113.300"), Err(IdentityError::InvalidEncoding(_))));
+        assert!(matches!(load("stolen laptop"), Err(IdentityError::InvalidEncoding(_))));
+        let key = IdentityKeypair::generate().public_key().to_string();
+        let content = format!("203.0.113.7\n{}\n", &key[2..]);
+        assert!(matches!(load(&content), Err(IdentityError::InvalidEncoding(_))));
+    }
+
+    #[test]
+    fn duplicate_entries_are_counted_once() {
+        let banned = IdentityKeypair::generate().public_key();
+        let content = format!("{banned}\n{banned} again\n203.0.113.7\n203.0.113.7 again\n");
+
+        let ban_list = load(&content).unwrap();
+
+        assert_eq!(ban_list.len(), 2);
+        assert!(ban_list.is_identity_banned(&banned));
+    }
+}
@@ -0,0 +1,109 @@
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use shared::{
+    encryption::{CipherSuite, RekeyPolicy},
+    handshake::{Capabilities, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, PreSharedKey},
+    framing::DEFAULT_MAX_FRAME_SIZE,
+    identity::IdentityKeypair,
+};
+
+use super::{BanList, Ticketer, TrustStore};
+
+pub struct ServerConfig {
+    /// Long-term key the server signs every handshake with
+    pub identity: IdentityKeypair,
+    /// Agents allowed to complete the handshake
+    pub trust_store: TrustStore,
+    /// Agents and addresses refused even when trusted
+    pub ban_list: BanList,
+    /// Enables the pre-shared key handshake mode when set
+    pub pre_shared_key: Option<PreSharedKey>,
+    /// When the sending key of a connection is automatically rotated
+    pub rekey_policy: RekeyPolicy,
+    /// Cipher suites accepted for the session, by order of preference
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Agent protocol versions accepted, older or newer agents are rejected during the handshake
+    pub protocol_versions: RangeInclusive<u16>,
+    /// Optional features enabled for agents supporting them
+    pub capabilities: Capabilities,
+    /// Features agents are rejected without, like `HYBRID_KEM` to always get post-quantum keys
+    pub required_capabilities: Capabilities,
+    /// Connections handled at once, agents connecting past it are rejected as overloaded
+    pub max_connections: usize,
+    /// Issues session resumption tickets, resumption is disabled when `None`
+    pub ticketer: Option<Ticketer>,
+    /// Limits after which a slow or silent connection is dropped
+    pub timeouts: Timeouts,
+    /// Largest frame accepted from agents, agents offering less lower it for their connection
+    pub max_frame_size: u32,
+    /// How long frames wait to be written together with the ones sent right after them
+    pub write_coalescing: WriteCoalescing,
+}
+
+/// Time limits of a connection, so peers that stop sending can't hold a task and a socket forever
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// From the connection to the end of the handshake, rejections included
+    pub handshake: Duration,
+    /// From the first byte of a frame to its last one
+    pub frame_read: Duration,
+    /// Between two frames, agents without traffic reconnect once it elapses
+    pub idle: Duration,
+    /// To write a batch of frames, an agent that stops reading is disconnected once it elapses
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: Duration::from_secs(10),
+            frame_read: Duration::from_secs(30),
+            idle: Duration::from_secs(5 * 60),
+            write: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Batching of the frames written to a connection, trading a bounded delay for fewer syscalls and
+/// TCP segments when many small packets are sent at once
+#[derive(Debug, Clone, Copy)]
+pub struct WriteCoalescing {
+    /// Longest a frame waits for others to be written with it, zero only batches frames already queued
+    pub max_delay: Duration,
+    /// Pending bytes after which a batch is written without waiting
+    pub max_batch_size: usize,
+}
+
+impl Default for WriteCoalescing {
+    fn default() -> Self {
+        WriteCoalescing {
+            max_delay: Duration::from_micros(500),
+            max_batch_size: 64 * 1024,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new(identity: IdentityKeypair, trust_store: TrustStore) -> Self {
+        ServerConfig {
+            identity,
+            trust_store,
+            ban_list: BanList::default(),
+            pre_shared_key: None,
+            rekey_policy: RekeyPolicy::default(),
+            cipher_suites: CipherSuite::ALL.to_vec(),
+            protocol_versions: MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION,
+            capabilities: Capabilities::DEFAULT,
+            required_capabilities: Capabilities::empty(),
+            max_connections: 1024,
+            ticketer: Some(Ticketer::new(
+                Duration::from_secs(12 * 60 * 60),
+                Duration::from_secs(60 * 60),
+            )),
+            timeouts: Timeouts::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            write_coalescing: WriteCoalescing::default(),
+        }
+    }
+}
@@ -1,5 +1,13 @@
+mod ban_list;
+mod config;
 mod logger;
 mod server;
+mod ticketer;
+mod trust_store;
 
+pub use ban_list::BanList;
+pub use config::{ServerConfig, Timeouts, WriteCoalescing};
 pub use logger::start_logger;
 pub use server::handle_connection;
+pub use ticketer::Ticketer;
+pub use trust_store::TrustStore;
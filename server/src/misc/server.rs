@@ -1,20 +1,79 @@
+use std::future::Future;
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::{net::TcpStream, sync::Semaphore, time::timeout};
 use tracing::info;
 
-use shared::error::NetworkError;
-use crate::network::{perform_handshake, MultiplexManager};
+use shared::{
+    encryption::SessionCipher,
+    error::NetworkError,
+    handshake::{Capabilities, RejectCode},
+    packets::NewSessionTicket,
+};
+use super::ServerConfig;
+use crate::network::{perform_handshake, reject_handshake, MultiplexManager};
 
-pub async fn handle_connection(stream: TcpStream) -> Result<(), NetworkError> {
+pub async fn handle_connection(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    connection_slots: Arc<Semaphore>,
+) -> Result<(), NetworkError> {
+    // Frames are batched by the writer task, Nagle would only delay small control packets
+    stream.set_nodelay(true)?;
     let (mut read_half, mut write_half) = stream.into_split();
     let ip = read_half.peer_addr()?;
-    let shared_secret = perform_handshake(&mut read_half, &mut write_half).await?;
+    let handshake_timeout = config.timeouts.handshake;
 
-    let manager = Arc::new(MultiplexManager::new(read_half, write_half, shared_secret));
+    if config.ban_list.is_address_banned(&ip.ip()) {
+        let message = format!("address {} is banned", ip.ip());
+        let code = RejectCode::Banned;
+        let reject = reject_handshake(&mut read_half, &mut write_half, code, &message);
+        return Err(timeout(handshake_timeout, reject)
+            .await
+            .unwrap_or(NetworkError::HandshakeTimeout(handshake_timeout)));
+    }
+
+    // Held until the connection ends
+    let Ok(_slot) = connection_slots.try_acquire_owned() else {
+        let message = format!("server is at its limit of {} connections", config.max_connections);
+        let code = RejectCode::Overloaded;
+        let reject = reject_handshake(&mut read_half, &mut write_half, code, &message);
+        return Err(timeout(handshake_timeout, reject)
+            .await
+            .unwrap_or(NetworkError::HandshakeTimeout(handshake_timeout)));
+    };
+
+    let handshake = perform_handshake(&mut read_half, &mut write_half, &config);
+    let outcome = with_timeout(handshake_timeout, handshake).await?;
+
+    let manager = Arc::new(MultiplexManager::new(
+        read_half,
+        write_half,
+        SessionCipher::server(outcome.keys, outcome.cipher_suite, config.rekey_policy),
+        outcome.capabilities,
+        outcome.max_frame_size,
+        config.timeouts,
+        config.write_coalescing,
+    )?);
 
     manager.start();
 
-    info!("Connection established with {}, opening streams...", ip);
+    if let Some(ticketer) = &config.ticketer {
+        let ticket = ticketer.issue(&outcome.resumption_secret, outcome.agent_identity, outcome.mode)?;
+        let lifetime = ticketer.lifetime().as_secs().try_into().unwrap_or(u32::MAX);
+        manager.send_control(NewSessionTicket::new(lifetime, ticket)).await?;
+    }
+
+    let key_exchange = match outcome.capabilities.contains(Capabilities::HYBRID_KEM) {
+        true => "X25519 + ML-KEM-768",
+        false => "X25519",
+    };
+
+    info!(
+        "Connection {} with {} (agent {}, {}, protocol v{}, {:?} mode, {}, {:?}), opening streams...",
+        if outcome.resumed { "resumed" } else { "established" },
+        ip, outcome.agent_name, outcome.agent_identity, outcome.version, outcome.mode, key_exchange, outcome.cipher_suite
+    );
 
     let mut handles = vec![];
     for i in 0..3 {
@@ -26,29 +85,40 @@ pub async fn handle_connection(stream: TcpStream) -> Result<(), NetworkError> {
 
                     for j in 0..5 {
                         let message = format!("Message {} from server stream {}", j, i);
-                        if let Err(e) = stream.send_bytes(message.as_bytes()).await {
+                        if let Err(e) = stream.send_bytes(message).await {
                             tracing::error!("Stream {}: failed to send: {}", i, e);
                             break;
                         }
                         info!("Stream {}: sent message {}", i, j);
 
                         match stream.receive().await {
-                            Ok(data) => {
+                            Ok(Some(data)) => {
                                 info!(
                                     "Stream {}: received echo: {:?}",
                                     i,
                                     String::from_utf8_lossy(&data)
                                 );
                             }
+                            Ok(None) => {
+                                info!("Stream {}: agent finished sending", i);
+                                break;
+                            }
+                            // Reset or lost with the connection, there is nothing left to finish
                             Err(e) => {
                                 tracing::error!("Stream {}: failed to receive: {}", i, e);
-                                break;
+                                return;
                             }
                         }
 
                         tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
                     }
 
+                    // The agent echoes until it sees the end of the stream, then finishes too
+                    if let Err(e) = stream.finish().await {
+                        tracing::error!("Stream {}: failed to finish: {}", i, e);
+                    }
+                    while let Ok(Some(_)) = stream.receive().await {}
+
                     info!("Stream {} finished", i);
                 }
                 Err(e) => {
@@ -66,3 +136,52 @@ pub async fn handle_connection(stream: TcpStream) -> Result<(), NetworkError> {
     info!("All streams finished for {}", ip);
     Ok(())
 }
+
+/// Run a handshake step, giving up with `NetworkError::HandshakeTimeout` once `limit` elapsed
+async fn with_timeout<T>(
+    limit: Duration,
+    handshake: impl Future<Output = Result<T, NetworkError>>,
+) -> Result<T, NetworkError> {
+    timeout(limit, handshake)
+        .await
+        .unwrap_or(Err(NetworkError::HandshakeTimeout(limit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::identity::IdentityKeypair;
+    use tokio::io::{AsyncWriteExt, duplex, split};
+
+    use crate::misc::TrustStore;
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_agents_time_out_the_handshake() {
+        let config = ServerConfig::new(IdentityKeypair::generate(), TrustStore::default());
+        let limit = config.timeouts.handshake;
+        let (_agent, server) = duplex(1024);
+        let (mut reader, mut writer) = split(server);
+
+        let handshake = perform_handshake(&mut reader, &mut writer, &config);
+        let started = tokio::time::Instant::now();
+        let result = with_timeout(limit, handshake).await;
+
+        assert!(matches!(result, Err(NetworkError::HandshakeTimeout(elapsed)) if elapsed == limit));
+        assert!(started.elapsed() >= limit);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partial_handshake_packets_time_out() {
+        let config = ServerConfig::new(IdentityKeypair::generate(), TrustStore::default());
+        let limit = config.timeouts.handshake;
+        let (mut agent, server) = duplex(1024);
+        let (mut reader, mut writer) = split(server);
+
+        // A length prefix announcing a packet that never comes
+        agent.write_all(&64u32.to_be_bytes()).await.unwrap();
+        let handshake = perform_handshake(&mut reader, &mut writer, &config);
+        let result = with_timeout(limit, handshake).await;
+
+        assert!(matches!(result, Err(NetworkError::HandshakeTimeout(_))));
+    }
+}
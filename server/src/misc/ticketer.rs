@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bincode::{Decode, Encode};
+use shared::{
+    encryption::{SecretKey, decrypt, encrypt},
+    error::NetworkError,
+    handshake::HandshakeMode,
+    identity::PublicIdentity,
+};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Size in bytes of the key id and nonce preceding the encrypted ticket contents
+const TICKET_HEADER_SIZE: usize = 4 + 12;
+
+/// Issues and opens session resumption tickets.
+///
+/// Tickets are encrypted with a server-only key replaced every `rotation`. Replaced keys are kept
+/// for `lifetime` so every ticket stays readable until it expires, then dropped: a leaked ticket
+/// key only exposes the tickets issued while it was current.
+pub struct Ticketer {
+    lifetime: Duration,
+    rotation: Duration,
+    keys: Mutex<VecDeque<TicketKey>>,
+}
+
+/// Session state recovered from a valid ticket
+pub struct ResumedSession {
+    pub resumption_secret: SecretKey,
+    pub agent_identity: PublicIdentity,
+    pub mode: HandshakeMode,
+}
+
+struct TicketKey {
+    id: u32,
+    key: SecretKey,
+    created_at: Instant,
+}
+
+#[derive(Encode, Decode, Zeroize, ZeroizeOnDrop)]
+struct TicketContents {
+    resumption_secret: [u8; 32],
+    agent_identity: [u8; 32],
+    #[zeroize(skip)]
+    mode: HandshakeMode,
+    /// Unix timestamp in seconds
+    issued_at: u64,
+}
+
+impl Ticketer {
+    pub fn new(lifetime: Duration, rotation: Duration) -> Self {
+        Ticketer {
+            lifetime,
+            rotation,
+            keys: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Encrypt a ticket resuming the session of `agent_identity`
+    pub fn issue(
+        &self,
+        resumption_secret: &SecretKey,
+        agent_identity: PublicIdentity,
+        mode: HandshakeMode,
+    ) -> Result<Vec<u8>, NetworkError> {
+        self.issue_at(resumption_secret, agent_identity, mode, unix_time())
+    }
+
+    fn issue_at(
+        &self,
+        resumption_secret: &SecretKey,
+        agent_identity: PublicIdentity,
+        mode: HandshakeMode,
+        issued_at: u64,
+    ) -> Result<Vec<u8>, NetworkError> {
+        let contents = TicketContents {
+            resumption_secret: *resumption_secret.expose_secret(),
+            agent_identity: agent_identity.to_bytes(),
+            mode,
+            issued_at,
+        };
+        let plaintext = bincode::encode_to_vec(&contents, bincode::config::standard())
+            .map(Zeroizing::new)
+            .map_err(|_| NetworkError::ConvertError)?;
+
+        let (id, key) = self.current_key()?;
+        let (ciphertext, nonce) = encrypt(&key, &plaintext)?;
+
+        let mut ticket = Vec::with_capacity(TICKET_HEADER_SIZE + ciphertext.len());
+        ticket.extend_from_slice(&id.to_be_bytes());
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&ciphertext);
+        Ok(ticket)
+    }
+
+    /// Decrypt a ticket, `None` if it's malformed, expired or its key was rotated out
+    pub fn open(&self, ticket: &[u8]) -> Option<ResumedSession> {
+        if ticket.len() < TICKET_HEADER_SIZE {
+            return None;
+        }
+        let (id, rest) = ticket.split_at(4);
+        let (nonce, ciphertext) = rest.split_at(12);
+        let id = u32::from_be_bytes(id.try_into().ok()?);
+
+        let key = {
+            let keys = self.keys.lock().ok()?;
+            keys.iter().find(|key| key.id == id)?.key.clone()
+        };
+        let plaintext = Zeroizing::new(decrypt(&key, nonce, ciphertext).ok()?);
+        let (contents, _): (TicketContents, _) =
+            bincode::decode_from_slice(&plaintext, bincode::config::standard()).ok()?;
+
+        if unix_time().saturating_sub(contents.issued_at) > self.lifetime.as_secs() {
+            return None;
+        }
+
+        Some(ResumedSession {
+            resumption_secret: SecretKey::from_bytes(contents.resumption_secret),
+            agent_identity: PublicIdentity::from_bytes(contents.agent_identity),
+            mode: contents.mode,
+        })
+    }
+
+    /// Key new tickets are encrypted with, rotating it and dropping the expired ones when due
+    fn current_key(&self) -> Result<(u32, SecretKey), NetworkError> {
+        let mut keys = self.keys.lock().map_err(|_| NetworkError::LockError)?;
+
+        let due = keys
+            .back()
+            .is_none_or(|key| key.created_at.elapsed() >= self.rotation);
+        if due {
+            let id = keys.back().map_or(0, |key| key.id.wrapping_add(1));
+            keys.push_back(TicketKey {
+                id,
+                key: SecretKey::random(),
+                created_at: Instant::now(),
+            });
+        }
+
+        // A key is retired when the next one is created, and useless once its last ticket expired
+        while keys.len() > 1 && keys[1].created_at.elapsed() > self.lifetime {
+            keys.pop_front();
+        }
+
+        let key = keys.back().expect("a ticket key was just created");
+        Ok((key.id, key.key.clone()))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::identity::IdentityKeypair;
+
+    const LIFETIME: Duration = Duration::from_secs(10);
+
+    fn ticketer() -> Ticketer {
+        Ticketer::new(LIFETIME, LIFETIME)
+    }
+
+    fn issue(ticketer: &Ticketer, secret: &SecretKey) -> Vec<u8> {
+        let agent = IdentityKeypair::generate().public_key();
+        ticketer.issue(secret, agent, HandshakeMode::Identity).unwrap()
+    }
+
+    /// Make every ticket key look older than it is, so rotation and retirement are due
+    fn age_keys(ticketer: &Ticketer, by: Duration) {
+        for key in ticketer.keys.lock().unwrap().iter_mut() {
+            key.created_at = key.created_at.checked_sub(by).unwrap();
+        }
+    }
+
+    #[test]
+    fn issued_tickets_open_to_the_same_session() {
+        let ticketer = ticketer();
+        let secret = SecretKey::random();
+        let agent = IdentityKeypair::generate().public_key();
+        let ticket = ticketer.issue(&secret, agent, HandshakeMode::PreSharedKey).unwrap();
+
+        let session = ticketer.open(&ticket).unwrap();
+        assert_eq!(session.resumption_secret.expose_secret(), secret.expose_secret());
+        assert_eq!(session.agent_identity.to_bytes(), agent.to_bytes());
+        assert_eq!(session.mode, HandshakeMode::PreSharedKey);
+    }
+
+    #[test]
+    fn tampered_tickets_are_rejected() {
+        let ticketer = ticketer();
+        let ticket = issue(&ticketer, &SecretKey::random());
+
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ticketer.open(&tampered).is_none());
+
+        let mut wrong_nonce = ticket.clone();
+        wrong_nonce[4] ^= 1;
+        assert!(ticketer.open(&wrong_nonce).is_none());
+
+        let mut unknown_key = ticket.clone();
+        unknown_key[3] ^= 1;
+        assert!(ticketer.open(&unknown_key).is_none());
+
+        assert!(ticketer.open(&ticket[..TICKET_HEADER_SIZE - 1]).is_none());
+        assert!(ticketer.open(&ticket[..ticket.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn expired_tickets_are_rejected() {
+        let ticketer = ticketer();
+        let agent = IdentityKeypair::generate().public_key();
+        let now = unix_time();
+        let recent = ticketer
+            .issue_at(&SecretKey::random(), agent, HandshakeMode::Identity, now - 5)
+            .unwrap();
+        let expired = ticketer
+            .issue_at(&SecretKey::random(), agent, HandshakeMode::Identity, now - 11)
+            .unwrap();
+
+        assert!(ticketer.open(&recent).is_some());
+        assert!(ticketer.open(&expired).is_none());
+    }
+
+    #[test]
+    fn rotated_out_keys_no_longer_open() {
+        let ticketer = ticketer();
+        let first = issue(&ticketer, &SecretKey::random());
+
+        // The rotation replaces the key, the old one still opens the tickets it sealed
+        age_keys(&ticketer, LIFETIME);
+        let second = issue(&ticketer, &SecretKey::random());
+        assert_ne!(first[..4], second[..4]);
+        assert!(ticketer.open(&first).is_some());
+
+        // Once its successor is older than a ticket lifetime, the first key is dropped
+        age_keys(&ticketer, LIFETIME + Duration::from_secs(1));
+        let third = issue(&ticketer, &SecretKey::random());
+        assert_eq!(ticketer.keys.lock().unwrap().len(), 2);
+        assert!(ticketer.open(&first).is_none());
+        assert!(ticketer.open(&second).is_some());
+        assert!(ticketer.open(&third).is_some());
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use shared::identity::{IdentityError, PublicIdentity};
+
+/// Allowlist of the agent identity keys allowed to connect.
+///
+/// The file holds one hex encoded public key per line, optionally followed by a name.
+/// Empty lines and lines starting with `#` are ignored:
+///
+/// ```text
+/// # build machines
+/// 3b6a27bcceb6a42d62a3a8d02a6f0d73653215771de243a63ac048a18b5c2c3f builder-01
+/// ```
+#[derive(Debug, Default)]
+pub struct TrustStore {
+    agents: HashMap<PublicIdentity, String>,
+}
+
+impl TrustStore {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, IdentityError> {
+        let content = fs::read_to_string(path)?;
+        let mut agents = HashMap::new();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let identity = PublicIdentity::from_hex(key)?;
+            let name = match name.trim() {
+                "" => identity.to_string(),
+                name => name.to_string(),
+            };
+            agents.insert(identity, name);
+        }
+
+        Ok(TrustStore { agents })
+    }
+
+    /// Load the trust store in `path`, creating an empty one if the file doesn't exist
+    pub fn load_or_create(path: impl AsRef<Path>) -> Result<Self, IdentityError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            fs::write(path, "# Trusted agent identity keys, one hex key per line followed by an optional name\n")?;
+        }
+        Self::load(path)
+    }
+
+    /// Name of a trusted agent, `None` if the key isn't in the store
+    pub fn agent_name(&self, identity: &PublicIdentity) -> Option<&str> {
+        self.agents.get(identity).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.agents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.agents.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::identity::IdentityKeypair;
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("trusted_agents_{}.txt", rand::random::<u64>()))
+    }
+
+    fn load(content: &str) -> Result<TrustStore, IdentityError> {
+        let path = temp_path();
+        fs::write(&path, content).unwrap();
+        let trust_store = TrustStore::load(&path);
+        fs::remove_file(&path).unwrap();
+        trust_store
+    }
+
+    #[test]
+    fn parses_keys_names_and_comments() {
+        let named = IdentityKeypair::generate().public_key();
+        let unnamed = IdentityKeypair::generate().public_key();
+        let untrusted = IdentityKeypair::generate().public_key();
+        let content = format!("# build machines\n\n  {named}\tbuilder 01  \n{unnamed}\n");
+
+        let trust_store = load(&content).unwrap();
+
+        assert_eq!(trust_store.len(), 2);
+        assert_eq!(trust_store.agent_name(&named), Some("builder 01"));
+        // Agents without a name are named after their key
+        assert_eq!(trust_store.agent_name(&unnamed), Some(unnamed.to_string().as_str()));
+        assert_eq!(trust_store.agent_name(&untrusted), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let key = IdentityKeypair::generate().public_key().to_string();
+
+        assert!(matches!(load("not-a-key builder"), Err(IdentityError::InvalidEncoding(_))));
+        // One byte short of a key
+        assert!(matches!(load(&key[2..]), Err(IdentityError::InvalidEncoding(_))));
+        // A single malformed line fails the whole file
+        let content = format!("{key} builder\n{}zz\n", &key[2..]);
+        assert!(matches!(load(&content), Err(IdentityError::InvalidEncoding(_))));
+    }
+
+    #[test]
+    fn duplicate_keys_keep_the_last_name() {
+        let agent = IdentityKeypair::generate().public_key();
+        let content = format!("{agent} old-name\n{agent} new-name\n");
+
+        let trust_store = load(&content).unwrap();
+
+        assert_eq!(trust_store.len(), 1);
+        assert_eq!(trust_store.agent_name(&agent), Some("new-name"));
+    }
+
+    #[test]
+    fn creates_an_empty_store() {
+        let path = temp_path();
+
+        let trust_store = TrustStore::load_or_create(&path).unwrap();
+        let created = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(trust_store.is_empty());
+        assert!(created.starts_with('#'));
+    }
+}
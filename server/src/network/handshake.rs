@@ -1,19 +1,57 @@
-use aes_gcm::aead::OsRng;
 use shared::{
     error::NetworkError,
-    encryption::encrypt,
-    packets::{EncryptionRequest, EncryptionResponse, Packet, Packets, from_packet_bytes},
+    framing::negotiate_frame_size,
+    encryption::{CipherSuite, KeySchedule, SecretKey, SessionKeys, decrypt, encrypt},
+    handshake::{
+        Capabilities, HandshakeMode, LENGTH_PREFIX_SIZE, RejectCode, Transcript, encode_handshake_packet,
+        handshake_packet_length, negotiate_version, peek_version_range,
+    },
+    identity::{PublicIdentity, SIGNATURE_SIZE},
+    key_exchange::ServerKeyShare,
+    packets::{
+        EncryptionResponse, HandshakeReject, Packet, Packets, from_packet_bytes,
+    },
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use x25519_dalek::{EphemeralSecret, PublicKey};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::misc::ServerConfig;
+
+/// Result of a successful handshake
+pub struct HandshakeOutcome {
+    pub keys: SessionKeys,
+    pub agent_identity: PublicIdentity,
+    pub agent_name: String,
+    pub mode: HandshakeMode,
+    pub cipher_suite: CipherSuite,
+    pub version: u16,
+    pub capabilities: Capabilities,
+    /// Whether the agent resumed a previous session with a ticket
+    pub resumed: bool,
+    /// Secret of the ticket resuming this session
+    pub resumption_secret: SecretKey,
+    /// Largest frame either peer may send
+    pub max_frame_size: u32,
+}
 
 pub async fn perform_handshake(
-    reader: &mut tokio::net::tcp::OwnedReadHalf,
-    writer: &mut tokio::net::tcp::OwnedWriteHalf,
-) -> Result<[u8; 32], NetworkError> {
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    config: &ServerConfig,
+) -> Result<HandshakeOutcome, NetworkError> {
     // Getting the encryption request from the client
-    let mut encryption_request_buffer = [0u8; EncryptionRequest::PACKET_SIZE];
-    reader.read_exact(&mut encryption_request_buffer).await?;
+    let encryption_request_buffer = read_handshake_packet(reader).await?;
+
+    // The version range is checked before decoding the rest of the request,
+    // whose layout may differ in versions we don't speak
+    let offered_versions =
+        peek_version_range(&encryption_request_buffer).ok_or(NetworkError::UnexpectedPacket)?;
+    let Some(version) = negotiate_version(&offered_versions, &config.protocol_versions) else {
+        let error = NetworkError::UnsupportedProtocolVersion {
+            offered: offered_versions,
+            supported: config.protocol_versions.clone(),
+        };
+        return Err(send_reject(writer, RejectCode::UnsupportedVersion, error).await);
+    };
 
     let encryption_request = match from_packet_bytes(&encryption_request_buffer) {
         Ok(Packets::EncryptionRequest(packet)) => packet,
@@ -24,33 +62,628 @@ pub async fn perform_handshake(
             return Err(NetworkError::PacketError(e));
         }
     };
-    let secret = EphemeralSecret::random_from_rng(OsRng);
-    let public_secret = PublicKey::from(&secret);
-    let shared_secret = secret
-        .diffie_hellman(&PublicKey::from(encryption_request.key))
-        .to_bytes();
+
+    // Without a configured pre-shared key the server only speaks the identity mode,
+    // the agent learns it from the response and aborts on its side
+    let mode = match (encryption_request.mode, &config.pre_shared_key) {
+        (HandshakeMode::PreSharedKey, Some(_)) => HandshakeMode::PreSharedKey,
+        _ => HandshakeMode::Identity,
+    };
+
+    // Without a common suite the response still carries our preferred one, so the agent can report it
+    let negotiated_suite =
+        CipherSuite::negotiate(&config.cipher_suites, encryption_request.cipher_suites);
+    let cipher_suite = negotiated_suite
+        .or(config.cipher_suites.first().copied())
+        .unwrap_or(CipherSuite::Aes256Gcm);
+
+    let max_frame_size =
+        negotiate_frame_size(encryption_request.max_frame_size, config.max_frame_size);
+
+    let mut capabilities = encryption_request.capabilities.intersection(config.capabilities);
+    if encryption_request.kem_key.is_none() {
+        capabilities = capabilities.difference(Capabilities::HYBRID_KEM);
+    }
+    let missing = config.required_capabilities.difference(capabilities);
+    if missing != Capabilities::empty() {
+        let error = NetworkError::CapabilityNotNegotiated(missing);
+        return Err(send_reject(writer, RejectCode::MissingCapabilities, error).await);
+    }
+
+    // Unknown agents are rejected before doing any key exchange work,
+    // in pre-shared key mode knowing the key is enough to be trusted.
+    // On a mode mismatch the response is still sent so the agent learns why the handshake fails.
+    let agent_identity = PublicIdentity::from_bytes(encryption_request.identity_key);
+    if config.ban_list.is_identity_banned(&agent_identity) {
+        let error =
+            NetworkError::AgentAuthenticationFailed(format!("agent {agent_identity} is banned"));
+        return Err(send_reject(writer, RejectCode::Banned, error).await);
+    }
+    let agent_name = match config.trust_store.agent_name(&agent_identity) {
+        Some(name) => name.to_string(),
+        None if mode == HandshakeMode::Identity && mode == encryption_request.mode => {
+            let error = NetworkError::AgentAuthenticationFailed(format!(
+                "agent {agent_identity} is not trusted"
+            ));
+            return Err(send_reject(writer, RejectCode::AuthenticationFailed, error).await);
+        }
+        None => agent_identity.to_string(),
+    };
+
+    // A valid ticket issued to this agent in the same mode resumes its session,
+    // any other ticket silently falls back to a full handshake
+    let resumed_session = match (&encryption_request.ticket, &config.ticketer) {
+        (Some(ticket), Some(ticketer)) if mode == encryption_request.mode => ticketer
+            .open(ticket)
+            .filter(|session| session.agent_identity == agent_identity && session.mode == mode),
+        _ => None,
+    };
+    let resumed = resumed_session.is_some();
+
+    // In hybrid mode the ML-KEM secret is mixed with the X25519 one
+    let kem_key = encryption_request
+        .kem_key
+        .as_deref()
+        .filter(|_| capabilities.contains(Capabilities::HYBRID_KEM));
+    let (key_share, shared_secret) = ServerKeyShare::respond(encryption_request.key, kem_key)?;
+    // The resumption secret replaces the pre-shared key, the DH exchange still makes the keys fresh
+    let salt = match (&resumed_session, mode) {
+        (Some(session), _) => Some(&session.resumption_secret),
+        (None, HandshakeMode::PreSharedKey) => config.pre_shared_key.as_ref().map(|key| key.secret()),
+        (None, HandshakeMode::Identity) => None,
+    };
+    let key_schedule = KeySchedule::new(&shared_secret, salt);
+
+    // The verify token, its nonce and the signature are filled in once the transcript is hashed
+    let mut response = EncryptionResponse::new(
+        version,
+        capabilities,
+        key_share.public_key,
+        [0; 12],
+        [0; 24],
+        config.identity.public_key().to_bytes(),
+        [0; SIGNATURE_SIZE],
+        mode,
+        cipher_suite,
+        key_share.kem_ciphertext,
+        resumed,
+        max_frame_size,
+    );
+
+    // Sign the exchanged keys so the agent can detect a man in the middle.
+    // A resumed session is authenticated by the resumption secret, signatures are skipped.
+    let mut transcript = Transcript::server_signed(&encryption_request_buffer, &response);
+    if !resumed {
+        response.signature = config.identity.sign(&transcript.hash());
+    }
+
+    let handshake_key = key_schedule.handshake_key(&transcript.hash());
     let (verified_token, nonce) = encrypt(
-        &shared_secret,
+        &handshake_key,
         &encryption_request.verify_token.to_be_bytes(),
     )?;
 
     // Rust shenanigans to conver the Vec<u8> to [u8; 24] and [u8; 12]
-    let verified_token_array: [u8; 24] = verified_token.as_slice().try_into().map_err(|_| {
+    response.verify_token = verified_token.as_slice().try_into().map_err(|_| {
         std::io::Error::new(
             std::io::ErrorKind::InvalidData,
             "Invalid verified_token length",
         )
     })?;
-    let nonce_array: [u8; 12] = nonce.as_slice().try_into().map_err(|_| {
+    response.nonce = nonce.as_slice().try_into().map_err(|_| {
         std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid nonce length")
     })?;
 
-    let response =
-        EncryptionResponse::new(public_secret.to_bytes(), nonce_array, verified_token_array);
+    write_handshake_packet(writer, &response).await?;
+
+    if mode != encryption_request.mode {
+        return Err(NetworkError::HandshakeModeMismatch {
+            requested: encryption_request.mode,
+            got: mode,
+        });
+    }
+
+    if negotiated_suite.is_none() {
+        return Err(NetworkError::NoCommonCipherSuite {
+            offered: CipherSuite::from_mask(encryption_request.cipher_suites),
+            got: cipher_suite,
+        });
+    }
+
+    // The agent proves it owns its identity key by signing the transcript, server signature included
+    let authentication_buffer = read_handshake_packet(reader).await?;
+
+    let authentication = match from_packet_bytes(&authentication_buffer) {
+        Ok(Packets::AgentAuthentication(packet)) => packet,
+        Ok(_) => {
+            return Err(NetworkError::UnexpectedPacket);
+        }
+        Err(e) => {
+            return Err(NetworkError::PacketError(e));
+        }
+    };
+
+    transcript.update(&response.signature);
+    let transcript_hash = transcript.hash();
+
+    // The verify data can only be decrypted if the agent derived the same keys,
+    // which in pre-shared key mode proves it knows the key
+    let verify_data = decrypt(&handshake_key, &authentication.nonce, &authentication.verify_data)
+        .map_err(|_| match mode {
+            HandshakeMode::PreSharedKey if !resumed => NetworkError::PreSharedKeyMismatch,
+            _ => NetworkError::AgentAuthenticationFailed("key confirmation failed".to_string()),
+        })?;
+    if verify_data != transcript_hash {
+        return Err(NetworkError::AgentAuthenticationFailed(
+            "verify data didn't match the transcript".to_string(),
+        ));
+    }
+
+    if !resumed {
+        agent_identity
+            .verify(&transcript_hash, &authentication.signature)
+            .map_err(|e| NetworkError::AgentAuthenticationFailed(e.to_string()))?;
+    }
+
+    Ok(HandshakeOutcome {
+        keys: key_schedule.session_keys(&transcript_hash),
+        agent_identity,
+        agent_name,
+        mode,
+        cipher_suite,
+        version,
+        capabilities,
+        resumed,
+        resumption_secret: key_schedule.resumption_secret(&transcript_hash),
+        max_frame_size,
+    })
+}
+
+/// Refuse a connection before the handshake starts, once the agent sent its Encryption Request.
+/// Reading the request first avoids resetting the connection before the agent reads the reject.
+pub async fn reject_handshake(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    code: RejectCode,
+    message: &str,
+) -> NetworkError {
+    if let Err(e) = read_handshake_packet(reader).await {
+        return e;
+    }
+
+    let reject = HandshakeReject::new(code, message);
+    if let Err(e) = write_handshake_packet(writer, &reject).await {
+        tracing::debug!("Failed to send handshake reject: {}", e);
+    }
+    NetworkError::HandshakeRejected {
+        code,
+        message: reject.message,
+    }
+}
+
+/// Tell the agent why the handshake failed, the returned error is `error` unchanged
+async fn send_reject(
+    writer: &mut (impl AsyncWrite + Unpin),
+    code: RejectCode,
+    error: NetworkError,
+) -> NetworkError {
+    let reject = HandshakeReject::new(code, error.to_string());
+    if let Err(e) = write_handshake_packet(writer, &reject).await {
+        tracing::debug!("Failed to send handshake reject: {}", e);
+    }
+    error
+}
+
+async fn read_handshake_packet(reader: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, NetworkError> {
+    let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+    reader.read_exact(&mut prefix).await?;
+
+    let mut packet = vec![0u8; handshake_packet_length(prefix)?];
+    reader.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+
+async fn write_handshake_packet(
+    writer: &mut (impl AsyncWrite + Unpin),
+    packet: &impl Packet,
+) -> Result<(), NetworkError> {
+    writer.write_all(&encode_handshake_packet(&packet.serialize()?)).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use shared::{
+        encryption::{RekeyPolicy, SessionCipher},
+        framing::DEFAULT_MAX_FRAME_SIZE,
+        handshake::{PROTOCOL_VERSION, PreSharedKey},
+        identity::IdentityKeypair,
+        key_exchange::AgentKeyShare,
+        packets::{AgentAuthentication, EncryptionRequest},
+    };
+    use tokio::io::{DuplexStream, duplex, split};
+
+    use crate::misc::{Ticketer, TrustStore};
+
+    /// What the test agent offers in its Encryption Request
+    struct AgentOffer {
+        capabilities: Capabilities,
+        cipher_suites: u8,
+        /// Key of the pre-shared key mode, the identity mode is used without one
+        pre_shared_key: Option<SecretKey>,
+        /// Ticket to resume a session with, and its resumption secret
+        ticket: Option<(Vec<u8>, SecretKey)>,
+    }
+
+    impl AgentOffer {
+        fn new(capabilities: Capabilities) -> Self {
+            AgentOffer {
+                capabilities,
+                cipher_suites: CipherSuite::to_mask(&CipherSuite::ALL),
+                pre_shared_key: None,
+                ticket: None,
+            }
+        }
+    }
+
+    /// What the agent derived from a successful handshake
+    struct AgentSession {
+        keys: SessionKeys,
+        response: EncryptionResponse,
+        resumption_secret: SecretKey,
+    }
+
+    /// Agent side of a handshake, accepting any server key. The stream is closed when it fails,
+    /// like an agent giving up on the connection.
+    async fn agent_handshake(
+        mut stream: DuplexStream,
+        identity: &IdentityKeypair,
+        offer: AgentOffer,
+    ) -> Result<AgentSession, NetworkError> {
+        let key_share =
+            AgentKeyShare::generate(offer.capabilities.contains(Capabilities::HYBRID_KEM));
+        let verify_token: u64 = rand::random();
+        let mode = match offer.pre_shared_key {
+            Some(_) => HandshakeMode::PreSharedKey,
+            None => HandshakeMode::Identity,
+        };
+        let request = EncryptionRequest::new(
+            offer.capabilities,
+            key_share.public_key(),
+            verify_token,
+            identity.public_key().to_bytes(),
+            mode,
+            offer.cipher_suites,
+            key_share.kem_key(),
+            offer.ticket.as_ref().map(|(ticket, _)| ticket.clone()),
+            DEFAULT_MAX_FRAME_SIZE,
+        )
+        .serialize()?;
+        stream.write_all(&encode_handshake_packet(&request)).await?;
+
+        let response = match from_packet_bytes(&read_handshake_packet(&mut stream).await?)? {
+            Packets::EncryptionResponse(response) => response,
+            Packets::HandshakeReject(reject) => {
+                return Err(NetworkError::HandshakeRejected {
+                    code: reject.code,
+                    message: reject.message,
+                });
+            }
+            _ => return Err(NetworkError::UnexpectedPacket),
+        };
+        if offer.cipher_suites & response.cipher_suite.bit() == 0 {
+            return Err(NetworkError::NoCommonCipherSuite {
+                offered: CipherSuite::from_mask(offer.cipher_suites),
+                got: response.cipher_suite,
+            });
+        }
+
+        let mut transcript = Transcript::server_signed(&request, &response);
+        if !response.resumed {
+            PublicIdentity::from_bytes(response.identity_key)
+                .verify(&transcript.hash(), &response.signature)
+                .map_err(|e| NetworkError::ServerAuthenticationFailed(e.to_string()))?;
+        }
+
+        let shared_secret = key_share.finish(response.key, response.kem_ciphertext.as_deref())?;
+        let salt = match &offer.ticket {
+            Some((_, resumption_secret)) if response.resumed => Some(resumption_secret),
+            _ => offer.pre_shared_key.as_ref(),
+        };
+        let key_schedule = KeySchedule::new(&shared_secret, salt);
+        let handshake_key = key_schedule.handshake_key(&transcript.hash());
+        // The server only encrypted the token with our keys if it knows the same pre-shared key
+        let token = decrypt(&handshake_key, &response.nonce, &response.verify_token).map_err(
+            |_| match response.mode {
+                HandshakeMode::PreSharedKey => NetworkError::PreSharedKeyMismatch,
+                got => NetworkError::HandshakeModeMismatch {
+                    requested: mode,
+                    got,
+                },
+            },
+        )?;
+        assert_eq!(token, verify_token.to_be_bytes());
+
+        transcript.update(&response.signature);
+        let transcript_hash = transcript.hash();
+        let (verify_data, nonce) = encrypt(&handshake_key, &transcript_hash)?;
+        let authentication = AgentAuthentication::new(
+            identity.sign(&transcript_hash),
+            nonce.try_into().expect("nonce of 12 bytes"),
+            verify_data.try_into().expect("verify data of 48 bytes"),
+        );
+        stream
+            .write_all(&encode_handshake_packet(&authentication.serialize()?))
+            .await?;
+
+        Ok(AgentSession {
+            keys: key_schedule.session_keys(&transcript_hash),
+            response,
+            resumption_secret: key_schedule.resumption_secret(&transcript_hash),
+        })
+    }
+
+    /// Run the server handshake against `agent_handshake`
+    async fn run_handshake(
+        config: &ServerConfig,
+        agent: &IdentityKeypair,
+        offer: AgentOffer,
+    ) -> (
+        Result<HandshakeOutcome, NetworkError>,
+        Result<AgentSession, NetworkError>,
+    ) {
+        let (agent_stream, server_stream) = duplex(64 * 1024);
+        let (mut reader, mut writer) = split(server_stream);
+        tokio::join!(
+            perform_handshake(&mut reader, &mut writer, config),
+            agent_handshake(agent_stream, agent, offer),
+        )
+    }
+
+    /// Check both peers derived the same session keys
+    fn assert_same_keys(agent_keys: SessionKeys, outcome: HandshakeOutcome) {
+        let policy = RekeyPolicy::default();
+        let mut agent_cipher = SessionCipher::client(agent_keys, outcome.cipher_suite, policy);
+        let mut server_cipher = SessionCipher::server(outcome.keys, outcome.cipher_suite, policy);
+        let frame = agent_cipher.sealing_key().seal(b"header", b"ping").unwrap();
+        let opened = server_cipher.opening_key().open(b"header", &frame).unwrap();
+        assert_eq!(opened, b"ping");
+    }
+
+    fn trust_store_with(agent: &IdentityKeypair) -> TrustStore {
+        let path = std::env::temp_dir().join(format!("trusted_agents_{}.txt", rand::random::<u64>()));
+        std::fs::write(&path, format!("{} test-agent\n", agent.public_key())).unwrap();
+        let trust_store = TrustStore::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        trust_store
+    }
+
+    #[tokio::test]
+    async fn handshake_round_trip() {
+        let agent = IdentityKeypair::generate();
+        let config = ServerConfig::new(IdentityKeypair::generate(), trust_store_with(&agent));
+
+        let offer = AgentOffer::new(Capabilities::SUPPORTED);
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
+        let outcome = outcome.unwrap();
+        let AgentSession { keys, response, .. } = agent_result.unwrap();
+
+        assert_eq!(outcome.agent_name, "test-agent");
+        assert_eq!(outcome.agent_identity, agent.public_key());
+        assert_eq!(outcome.version, PROTOCOL_VERSION);
+        // Compression stays off unless the server opts in
+        assert_eq!(outcome.capabilities, Capabilities::DEFAULT);
+        assert_eq!(response.capabilities, outcome.capabilities);
+        assert_eq!(response.cipher_suite, outcome.cipher_suite);
+        assert!(!outcome.resumed);
+        assert_same_keys(keys, outcome);
+    }
+
+    #[tokio::test]
+    async fn resumes_session_with_ticket() {
+        let agent = IdentityKeypair::generate();
+        let mut config = ServerConfig::new(IdentityKeypair::generate(), trust_store_with(&agent));
+        config.ticketer = Some(Ticketer::new(Duration::from_secs(60), Duration::from_secs(60)));
+
+        let offer = AgentOffer::new(Capabilities::DEFAULT);
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
+        let outcome = outcome.unwrap();
+        let session = agent_result.unwrap();
+        assert_eq!(
+            session.resumption_secret.expose_secret(),
+            outcome.resumption_secret.expose_secret()
+        );
+        let ticketer = config.ticketer.as_ref().unwrap();
+        let ticket = ticketer
+            .issue(&outcome.resumption_secret, outcome.agent_identity, outcome.mode)
+            .unwrap();
+
+        let mut offer = AgentOffer::new(Capabilities::DEFAULT);
+        offer.ticket = Some((ticket, session.resumption_secret));
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
+        let outcome = outcome.unwrap();
+        let AgentSession { keys, response, .. } = agent_result.unwrap();
+
+        assert!(outcome.resumed);
+        assert!(response.resumed);
+        assert_eq!(outcome.agent_name, "test-agent");
+        assert_same_keys(keys, outcome);
+    }
+
+    #[tokio::test]
+    async fn unreadable_ticket_falls_back_to_full_handshake() {
+        let agent = IdentityKeypair::generate();
+        let mut config = ServerConfig::new(IdentityKeypair::generate(), trust_store_with(&agent));
+        config.ticketer = Some(Ticketer::new(Duration::from_secs(60), Duration::from_secs(60)));
+
+        let mut offer = AgentOffer::new(Capabilities::DEFAULT);
+        offer.ticket = Some((vec![0; 64], SecretKey::random()));
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
+        let outcome = outcome.unwrap();
+        let AgentSession { keys, response, .. } = agent_result.unwrap();
+
+        assert!(!outcome.resumed);
+        assert!(!response.resumed);
+        assert_same_keys(keys, outcome);
+    }
+
+    #[tokio::test]
+    async fn negotiates_classic_key_exchange_without_kem_key() {
+        let agent = IdentityKeypair::generate();
+        let config = ServerConfig::new(IdentityKeypair::generate(), trust_store_with(&agent));
+
+        let capabilities = Capabilities::DEFAULT.difference(Capabilities::HYBRID_KEM);
+        let (outcome, agent_result) =
+            run_handshake(&config, &agent, AgentOffer::new(capabilities)).await;
+
+        assert!(!outcome.unwrap().capabilities.contains(Capabilities::HYBRID_KEM));
+        assert!(agent_result.unwrap().response.kem_ciphertext.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_agent() {
+        let config = ServerConfig::new(IdentityKeypair::generate(), TrustStore::default());
+
+        let agent = IdentityKeypair::generate();
+        let (outcome, agent_result) =
+            run_handshake(&config, &agent, AgentOffer::new(Capabilities::DEFAULT)).await;
+
+        assert!(matches!(outcome, Err(NetworkError::AgentAuthenticationFailed(_))));
+        assert!(matches!(
+            agent_result,
+            Err(NetworkError::HandshakeRejected { code: RejectCode::AuthenticationFailed, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_version() {
+        let agent = IdentityKeypair::generate();
+        let mut config = ServerConfig::new(IdentityKeypair::generate(), trust_store_with(&agent));
+        config.protocol_versions = PROTOCOL_VERSION + 1..=PROTOCOL_VERSION + 1;
+
+        let (outcome, agent_result) =
+            run_handshake(&config, &agent, AgentOffer::new(Capabilities::DEFAULT)).await;
+
+        assert!(matches!(outcome, Err(NetworkError::UnsupportedProtocolVersion { .. })));
+        assert!(matches!(
+            agent_result,
+            Err(NetworkError::HandshakeRejected { code: RejectCode::UnsupportedVersion, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_capabilities() {
+        let agent = IdentityKeypair::generate();
+        let mut config = ServerConfig::new(IdentityKeypair::generate(), trust_store_with(&agent));
+        config.required_capabilities = Capabilities::HYBRID_KEM;
+
+        let capabilities = Capabilities::DEFAULT.difference(Capabilities::HYBRID_KEM);
+        let (outcome, agent_result) =
+            run_handshake(&config, &agent, AgentOffer::new(capabilities)).await;
+
+        assert!(matches!(outcome, Err(NetworkError::CapabilityNotNegotiated(_))));
+        assert!(matches!(
+            agent_result,
+            Err(NetworkError::HandshakeRejected { code: RejectCode::MissingCapabilities, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn negotiates_the_preferred_common_suite() {
+        let agent = IdentityKeypair::generate();
+        let mut config = ServerConfig::new(IdentityKeypair::generate(), trust_store_with(&agent));
+        config.cipher_suites = vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305];
+
+        let mut offer = AgentOffer::new(Capabilities::DEFAULT);
+        offer.cipher_suites = CipherSuite::ChaCha20Poly1305.bit();
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
+        let outcome = outcome.unwrap();
+        let AgentSession { keys, response, .. } = agent_result.unwrap();
+
+        assert_eq!(outcome.cipher_suite, CipherSuite::ChaCha20Poly1305);
+        assert_eq!(response.cipher_suite, CipherSuite::ChaCha20Poly1305);
+        assert_same_keys(keys, outcome);
+    }
+
+    #[tokio::test]
+    async fn rejects_without_common_suite() {
+        let agent = IdentityKeypair::generate();
+        let mut config = ServerConfig::new(IdentityKeypair::generate(), trust_store_with(&agent));
+        config.cipher_suites = vec![CipherSuite::ChaCha20Poly1305];
+
+        let mut offer = AgentOffer::new(Capabilities::DEFAULT);
+        offer.cipher_suites = CipherSuite::Aes256Gcm.bit();
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
+
+        assert!(matches!(
+            outcome,
+            Err(NetworkError::NoCommonCipherSuite { got: CipherSuite::ChaCha20Poly1305, .. })
+        ));
+        // The response still names the server's suite so the agent can report it
+        assert!(matches!(
+            agent_result,
+            Err(NetworkError::NoCommonCipherSuite { got: CipherSuite::ChaCha20Poly1305, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn pre_shared_key_handshake_round_trip() {
+        let key = SecretKey::random();
+        let mut config = ServerConfig::new(IdentityKeypair::generate(), TrustStore::default());
+        config.pre_shared_key = Some(PreSharedKey::new(key.clone()));
+
+        // Knowing the key is enough, the agent isn't in the trust store
+        let agent = IdentityKeypair::generate();
+        let mut offer = AgentOffer::new(Capabilities::DEFAULT);
+        offer.pre_shared_key = Some(key);
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
+        let outcome = outcome.unwrap();
+        let AgentSession { keys, response, .. } = agent_result.unwrap();
+
+        assert_eq!(outcome.mode, HandshakeMode::PreSharedKey);
+        assert_eq!(response.mode, HandshakeMode::PreSharedKey);
+        assert_eq!(outcome.agent_name, agent.public_key().to_string());
+        assert_same_keys(keys, outcome);
+    }
+
+    #[tokio::test]
+    async fn pre_shared_key_mismatch_fails_both_peers() {
+        let mut config = ServerConfig::new(IdentityKeypair::generate(), TrustStore::default());
+        config.pre_shared_key = Some(PreSharedKey::new(SecretKey::random()));
+
+        let agent = IdentityKeypair::generate();
+        let mut offer = AgentOffer::new(Capabilities::DEFAULT);
+        offer.pre_shared_key = Some(SecretKey::random());
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
+
+        assert!(matches!(agent_result, Err(NetworkError::PreSharedKeyMismatch)));
+        // The agent hangs up instead of proving keys it doesn't share
+        assert!(matches!(outcome, Err(NetworkError::IoError(_))));
+    }
 
-    let serialized_response = response.serialize()?;
+    #[tokio::test]
+    async fn pre_shared_key_mode_needs_a_configured_key() {
+        let config = ServerConfig::new(IdentityKeypair::generate(), TrustStore::default());
 
-    writer.write_all(&serialized_response).await?;
+        let agent = IdentityKeypair::generate();
+        let mut offer = AgentOffer::new(Capabilities::DEFAULT);
+        offer.pre_shared_key = Some(SecretKey::random());
+        let (outcome, agent_result) = run_handshake(&config, &agent, offer).await;
 
-    Ok(shared_secret)
+        assert!(matches!(
+            outcome,
+            Err(NetworkError::HandshakeModeMismatch {
+                requested: HandshakeMode::PreSharedKey,
+                got: HandshakeMode::Identity,
+            })
+        ));
+        assert!(matches!(
+            agent_result,
+            Err(NetworkError::HandshakeModeMismatch { got: HandshakeMode::Identity, .. })
+        ));
+    }
 }
@@ -1,7 +1,9 @@
 mod handshake;
 mod stream;
 mod multiplex;
+mod writer;
 
-pub use handshake::perform_handshake;
+pub use handshake::{perform_handshake, reject_handshake};
+#[allow(unused_imports)]
 pub use stream::Stream;
 pub use multiplex::MultiplexManager;
@@ -1,202 +1,558 @@
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex, MutexGuard};
+
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-    sync::{Mutex, mpsc},
+    sync::{Mutex, Notify, mpsc},
+    time::timeout,
 };
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
 
 use shared::{
-    encryption::{decrypt, encrypt},
+    encryption::SessionCipher,
     error::NetworkError,
-    multiplexing::{MIN_DATA_STREAM_ID, StreamId},
-    packets::{Packet, Packets, StreamClose, StreamData, StreamOpen, from_packet_bytes},
+    framing::{FrameCodec, FrameDecoder, FrameEncoder},
+    handshake::Capabilities,
+    multiplexing::{Event, Multiplexer, READ_BUFFER_SIZE, ResetCode, Role, StreamId},
+    packets::Packets,
 };
 
-use super::stream::Stream;
+use super::stream::{Incoming, Stream};
+use super::writer::{WRITE_QUEUE_CAPACITY, write_loop};
+use crate::misc::{Timeouts, WriteCoalescing};
+
+/// Streams opened by the agent and not accepted yet, more are reset with `ResetCode::Refused`
+pub const ACCEPT_QUEUE_CAPACITY: usize = 100;
 
+/// Drives the `Multiplexer` of a connection over tokio
 pub struct MultiplexManager {
-    reader: Mutex<OwnedReadHalf>,
-    writer: Arc<Mutex<OwnedWriteHalf>>,
-    shared_secret: [u8; 32],
-    streams: Arc<Mutex<HashMap<StreamId, mpsc::Sender<Vec<u8>>>>>,
-    next_id: AtomicU32,
+    peer_addr: SocketAddr,
+    reader: Mutex<FramedRead<OwnedReadHalf, FrameDecoder>>,
+    // Packets are popped from the multiplexer while holding the outgoing half, so they are
+    // encrypted and queued in the order they were sent. The socket itself is owned by the writer
+    // task, and the multiplexer is never held across an await.
+    outgoing: Mutex<Outgoing>,
+    multiplexer: StdMutex<Multiplexer>,
+    timeouts: Timeouts,
+    // Cancelled once either half of the connection stops, to stop the other one
+    closing: CancellationToken,
+    // Wakes up senders waiting for the agent to grant more credit
+    window_updated: Notify,
+    // Unbounded, the receive windows bound what the agent can send before streams are read.
+    // Its sender is dropped once the agent finished sending or reset the stream.
+    streams: Mutex<HashMap<StreamId, mpsc::UnboundedSender<Incoming>>>,
     incoming_streams_tx: mpsc::Sender<Stream>,
     incoming_streams_rx: Arc<Mutex<mpsc::Receiver<Stream>>>,
 }
 
+/// Encrypts packets into frames for the writer task
+struct Outgoing {
+    encoder: FrameEncoder,
+    buffer: BytesMut,
+    frames: mpsc::Sender<Bytes>,
+}
+
 impl MultiplexManager {
-    pub fn new(reader: OwnedReadHalf, writer: OwnedWriteHalf, shared_secret: [u8; 32]) -> Self {
-        let (incoming_tx, incoming_rx) = mpsc::channel(100);
-
-        Self {
-            reader: Mutex::new(reader),
-            writer: Arc::new(Mutex::new(writer)),
-            shared_secret,
-            streams: Arc::new(Mutex::new(HashMap::new())),
-            next_id: AtomicU32::new(MIN_DATA_STREAM_ID),
+    /// Set up the connection and spawn the task writing its frames
+    pub fn new(
+        reader: OwnedReadHalf,
+        writer: OwnedWriteHalf,
+        cipher: SessionCipher,
+        capabilities: Capabilities,
+        max_frame_size: u32,
+        timeouts: Timeouts,
+        coalescing: WriteCoalescing,
+    ) -> Result<Self, NetworkError> {
+        let peer_addr = reader.peer_addr()?;
+        let (incoming_tx, incoming_rx) = mpsc::channel(ACCEPT_QUEUE_CAPACITY);
+        let (frames_tx, frames_rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+        let (encoder, decoder) = FrameCodec::new(cipher, capabilities, max_frame_size).into_split();
+
+        let closing = CancellationToken::new();
+        let writer_closing = closing.clone();
+        tokio::spawn(async move {
+            let closing = writer_closing.clone();
+            if let Err(e) = write_loop(writer, frames_rx, coalescing, timeouts.write, closing).await {
+                tracing::error!("Failed to write to {}: {}", peer_addr, e);
+            }
+            writer_closing.cancel();
+        });
+
+        Ok(Self {
+            peer_addr,
+            reader: Mutex::new(FramedRead::with_capacity(reader, decoder, READ_BUFFER_SIZE)),
+            outgoing: Mutex::new(Outgoing {
+                encoder,
+                buffer: BytesMut::with_capacity(READ_BUFFER_SIZE),
+                frames: frames_tx,
+            }),
+            multiplexer: StdMutex::new(Multiplexer::new(Role::Server, capabilities, max_frame_size)),
+            timeouts,
+            closing,
+            window_updated: Notify::new(),
+            streams: Mutex::new(HashMap::new()),
             incoming_streams_tx: incoming_tx,
             incoming_streams_rx: Arc::new(Mutex::new(incoming_rx)),
-        }
+        })
     }
 
     pub fn start(self: &Arc<Self>) {
         let self_clone = self.clone();
         tokio::spawn(async move {
-            if let Err(e) = self_clone.receive_loop().await {
-                tracing::error!("Multiplex receive loop error: {}", e);
+            let peer_addr = self_clone.peer_addr;
+            let result = tokio::select! {
+                result = self_clone.receive_loop() => result,
+                // The writer task failed
+                _ = self_clone.closing.cancelled() => Ok(()),
+            };
+            match result {
+                Err(NetworkError::IdleTimeout(idle)) => {
+                    tracing::info!("Closing connection with {} idle for {:?}", peer_addr, idle);
+                }
+                Err(NetworkError::FrameReadTimeout(limit)) => {
+                    tracing::warn!(
+                        "Closing connection with {}, a frame took more than {:?} to arrive",
+                        peer_addr,
+                        limit
+                    );
+                }
+                Err(e) => tracing::error!("Multiplex receive loop error: {}", e),
+                Ok(()) => {}
             }
+            self_clone.shutdown().await;
         });
     }
 
     pub async fn open_stream(self: &Arc<Self>) -> Result<Stream, NetworkError> {
-        let stream_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (stream_tx, stream_rx) = mpsc::unbounded_channel();
 
-        let (stream_tx, stream_rx) = mpsc::channel(100);
-
-        {
+        let stream_id = {
             let mut streams = self.streams.lock().await;
-            if streams.contains_key(&stream_id) {
-                return Err(NetworkError::StreamAlreadyExists(stream_id));
-            }
+            let stream_id = self.multiplexer()?.open_stream()?;
             streams.insert(stream_id, stream_tx);
-        }
-
-        let open_packet = StreamOpen { stream_id };
-        self.send_packet(&open_packet.serialize()?).await?;
+            stream_id
+        };
+        self.flush().await?;
 
         Ok(Stream::new(stream_id, self.clone(), stream_rx))
     }
 
+    #[allow(dead_code)]
     pub async fn accept_stream(&self) -> Result<Stream, NetworkError> {
         let mut rx = self.incoming_streams_rx.lock().await;
         rx.recv().await.ok_or(NetworkError::ChannelReceiveError)
     }
 
+    /// Send `data` on a stream, waiting for the agent to grant credit when its window is full
     pub async fn send_on_stream(
         &self,
         stream_id: StreamId,
-        data: Vec<u8>,
+        mut data: Bytes,
     ) -> Result<(), NetworkError> {
-        let packet = StreamData { stream_id, data };
-        self.send_packet(&packet.serialize()?).await
+        loop {
+            // Registered before sending so a window update received meanwhile isn't missed
+            let window_updated = self.window_updated.notified();
+            tokio::pin!(window_updated);
+            window_updated.as_mut().enable();
+
+            self.multiplexer()?.send(stream_id, &mut data)?;
+            self.flush().await?;
+            if data.is_empty() {
+                return Ok(());
+            }
+
+            tokio::select! {
+                _ = window_updated => {}
+                _ = self.closing.cancelled() => return Err(NetworkError::StreamClosed(stream_id)),
+            }
+        }
     }
 
+    /// Give back the credit of data read from a stream
+    pub async fn release(&self, stream_id: StreamId, length: usize) -> Result<(), NetworkError> {
+        self.multiplexer()?.release(stream_id, length);
+        self.flush().await
+    }
+
+    /// Finish sending on a stream, the agent can still send until it finishes too
+    pub async fn finish_stream(&self, stream_id: StreamId) -> Result<(), NetworkError> {
+        self.multiplexer()?.finish(stream_id)?;
+        self.flush().await
+    }
+
+    /// End both directions of a stream at once, telling the agent why with `code`
+    pub async fn reset_stream(
+        &self,
+        stream_id: StreamId,
+        code: ResetCode,
+    ) -> Result<(), NetworkError> {
+        self.streams.lock().await.remove(&stream_id);
+        self.multiplexer()?.reset(stream_id, code)?;
+        self.flush().await
+    }
+
+    /// Stop using a stream in both directions, see `Multiplexer::close_stream`
     pub async fn close_stream(&self, stream_id: StreamId) -> Result<(), NetworkError> {
-        {
-            let mut streams = self.streams.lock().await;
-            streams.remove(&stream_id);
+        self.streams.lock().await.remove(&stream_id);
+        self.multiplexer()?.close_stream(stream_id)?;
+        self.flush().await
+    }
+
+    /// Close a stream without waiting, for streams dropped outside of a task. Its Stream Close or
+    /// Stream Reset is sent by a spawned task, or along the next packets without a runtime.
+    pub fn close_stream_in_background(self: &Arc<Self>, stream_id: StreamId) {
+        let Ok(mut multiplexer) = self.multiplexer() else {
+            return;
+        };
+        // Both directions already ended
+        if multiplexer.close_stream(stream_id).is_err() {
+            return;
+        }
+        drop(multiplexer);
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let manager = self.clone();
+            runtime.spawn(async move {
+                manager.streams.lock().await.remove(&stream_id);
+                if let Err(e) = manager.flush().await {
+                    tracing::debug!("Failed to close stream {}: {}", stream_id, e);
+                }
+            });
         }
+    }
+
+    /// Send a packet on the control stream
+    pub async fn send_control(&self, packet: impl Into<Packets>) -> Result<(), NetworkError> {
+        self.multiplexer()?.send_control(packet)?;
+        self.flush().await
+    }
 
-        let close_packet = StreamClose { stream_id };
-        self.send_packet(&close_packet.serialize()?).await
+    /// Switch our sending key to its next generation, and ask the agent to do the same
+    #[allow(dead_code)]
+    pub async fn rekey(&self) -> Result<(), NetworkError> {
+        self.multiplexer()?.rekey()?;
+        self.flush().await
     }
 
     async fn receive_loop(self: &Arc<Self>) -> Result<(), NetworkError> {
-        loop {
-            let data = self.receive_packet().await?;
+        let mut reader = self.reader.lock().await;
+        let Timeouts { idle, frame_read, .. } = self.timeouts;
 
-            let packet = from_packet_bytes(&data)?;
+        loop {
+            // The next frame may take up to the idle timeout to start,
+            // then it must arrive in full within the frame read timeout
+            if reader.read_buffer().is_empty() {
+                timeout(idle, reader.get_ref().readable())
+                    .await
+                    .map_err(|_| NetworkError::IdleTimeout(idle))??;
+            }
+            let packet = match timeout(frame_read, reader.next()).await {
+                Ok(Some(packet)) => packet?,
+                Ok(None) => {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+                // Readiness can be spurious, nothing of a frame was received yet
+                Err(_) if reader.read_buffer().is_empty() => continue,
+                Err(_) => return Err(NetworkError::FrameReadTimeout(frame_read)),
+            };
+
+            let events = {
+                let mut multiplexer = self.multiplexer()?;
+                multiplexer.receive(packet)?;
+                std::iter::from_fn(|| multiplexer.poll_event()).collect::<Vec<_>>()
+            };
+
+            for event in events {
+                self.handle_event(event).await?;
+            }
+            // Answers to key update requests
+            self.flush().await?;
+        }
+    }
 
-            match packet {
-                Packets::StreamOpen(open) => {
-                    self.handle_stream_open(open).await?;
+    async fn handle_event(self: &Arc<Self>, event: Event) -> Result<(), NetworkError> {
+        match event {
+            Event::StreamOpened(stream_id) => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                self.streams.lock().await.insert(stream_id, tx);
+
+                let stream = Stream::new(stream_id, self.clone(), rx);
+                // Never waits, a full queue would stop the receive loop for every stream
+                if let Err(refused) = self.incoming_streams_tx.try_send(stream) {
+                    tracing::warn!("Refused stream {} of {}: {}", stream_id, self.peer_addr, refused);
+                    self.streams.lock().await.remove(&stream_id);
+                    self.multiplexer()?.reset(stream_id, ResetCode::Refused)?;
+                    // Only dropped once reset, so it doesn't finish the stream first
+                    drop(refused);
                 }
-                Packets::StreamClose(close) => {
-                    self.handle_stream_close(close).await?;
+            }
+            Event::StreamData { stream_id, data } => {
+                let streams = self.streams.lock().await;
+                let length = data.len();
+                let queued = streams
+                    .get(&stream_id)
+                    .is_some_and(|tx| tx.send(Ok(Some(data))).is_ok());
+                // Nobody will read it, so its credit is given back right away
+                if !queued {
+                    self.multiplexer()?.release(stream_id, length);
                 }
-                Packets::StreamData(data_packet) => {
-                    self.handle_stream_data(data_packet).await?;
+            }
+            Event::StreamFinished(stream_id) => {
+                // Its stream reads what is left, then the end of the stream
+                if let Some(tx) = self.streams.lock().await.remove(&stream_id) {
+                    let _ = tx.send(Ok(None));
                 }
-                Packets::StreamError(error) => {
-                    tracing::error!("Stream {} error: {}", error.stream_id, error.error);
+            }
+            Event::StreamReset { stream_id, code } => {
+                tracing::debug!("Agent reset stream {} ({:?})", stream_id, code);
+                // Its stream reads what was queued before, then the reset
+                if let Some(tx) = self.streams.lock().await.remove(&stream_id) {
+                    let _ = tx.send(Err(code));
                 }
-                _ => {
-                    tracing::warn!("Unexpected packet in multiplex receive loop");
+                // Senders waiting for credit on it fail
+                self.window_updated.notify_waiters();
+            }
+            Event::StreamFailed { stream_id, code, error } => {
+                tracing::warn!("Reset stream {} of {}: {}", stream_id, self.peer_addr, error);
+                if let Some(tx) = self.streams.lock().await.remove(&stream_id) {
+                    let _ = tx.send(Err(code));
                 }
+                self.window_updated.notify_waiters();
+            }
+            Event::WindowUpdated(_) => {
+                self.window_updated.notify_waiters();
+            }
+            Event::StreamError { stream_id, error } => {
+                tracing::error!("Stream {} error: {}", stream_id, error);
+            }
+            Event::PeerKeyUpdated { generation } => {
+                tracing::debug!("Agent rotated its sending key to generation {}", generation);
+            }
+            Event::UnknownStream(stream_id) => {
+                tracing::warn!("Received data for unknown stream: {}", stream_id);
+            }
+            Event::SessionTicket { .. } | Event::UnexpectedPacket => {
+                tracing::warn!("Unexpected packet in multiplex receive loop");
             }
         }
+        Ok(())
     }
 
-    async fn handle_stream_open(self: &Arc<Self>, open: StreamOpen) -> Result<(), NetworkError> {
-        let stream_id = open.stream_id;
-
-        let (tx, rx) = mpsc::channel(100);
+    /// Encrypt the packets queued by the multiplexer and queue their frames for the writer task,
+    /// waiting only when it is too far behind. Fails once the connection is closing, so a writer
+    /// stuck on an agent that stopped reading never holds the outgoing half past its write timeout.
+    ///
+    /// Packets are popped one at a time, those after one that fails to encode stay queued for
+    /// the next flush.
+    async fn flush(&self) -> Result<(), NetworkError> {
+        let mut outgoing = self.outgoing.lock().await;
+        let Outgoing { encoder, buffer, frames } = &mut *outgoing;
+        let generation = encoder.generation();
 
-        {
-            let mut streams = self.streams.lock().await;
-            if streams.contains_key(&stream_id) {
-                return Err(NetworkError::StreamAlreadyExists(stream_id));
+        loop {
+            let Some(packet) = self.multiplexer()?.poll_transmit() else {
+                break;
+            };
+            encoder.encode_packet(&packet, buffer)?;
+            let frame = buffer.split().freeze();
+            tokio::select! {
+                sent = frames.send(frame) => sent.map_err(|_| NetworkError::ChannelSendError)?,
+                _ = self.closing.cancelled() => return Err(NetworkError::ChannelSendError),
             }
-            streams.insert(stream_id, tx.clone());
         }
 
-        let stream = Stream::new(stream_id, self.clone(), rx);
-        self.incoming_streams_tx
-            .send(stream)
-            .await
-            .map_err(|_| NetworkError::ChannelSendError)?;
-
+        if encoder.generation() != generation {
+            tracing::debug!("Rotated sending key to generation {}", encoder.generation());
+        }
         Ok(())
     }
 
-    async fn handle_stream_close(&self, close: StreamClose) -> Result<(), NetworkError> {
-        let mut streams = self.streams.lock().await;
-        streams.remove(&close.stream_id);
-        Ok(())
+    /// Close the connection once the receive loop ended, waking up every stream waiting on it.
+    /// The writer task sends the frames already queued, then shuts the socket down.
+    async fn shutdown(&self) {
+        self.streams.lock().await.clear();
+        self.closing.cancel();
     }
 
-    async fn handle_stream_data(&self, data: StreamData) -> Result<(), NetworkError> {
-        let streams = self.streams.lock().await;
+    fn multiplexer(&self) -> Result<MutexGuard<'_, Multiplexer>, NetworkError> {
+        self.multiplexer.lock().map_err(|_| NetworkError::LockError)
+    }
+}
 
-        if let Some(tx) = streams.get(&data.stream_id) {
-            tx.send(data.data)
-                .await
-                .map_err(|_| NetworkError::ChannelSendError)?;
-        } else {
-            tracing::warn!("Received data for unknown stream: {}", data.stream_id);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use shared::{
+        encryption::{CipherSuite, KeySchedule, RekeyPolicy},
+        framing::{DEFAULT_MAX_FRAME_SIZE, FRAME_VERSION},
+        key_exchange::{AgentKeyShare, ServerKeyShare, SharedSecret},
+        multiplexing::INITIAL_STREAM_WINDOW,
+        packets::{
+            Heartbeat, StreamData, StreamOpen, StreamReset, WindowUpdate, from_packet_buf,
+        },
+    };
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    type Agent = Framed<TcpStream, FrameCodec>;
+
+    /// Started manager of a local connection, and the agent end of it sending raw packets
+    async fn connection(timeouts: Timeouts) -> (Arc<MultiplexManager>, Agent) {
+        let agent_share = AgentKeyShare::generate(false);
+        let (server_share, server_secret) =
+            ServerKeyShare::respond(agent_share.public_key(), None).unwrap();
+        let agent_secret = agent_share.finish(server_share.public_key, None).unwrap();
+        let keys = |secret: &SharedSecret| KeySchedule::new(secret, None).session_keys(&[7; 32]);
+        let (suite, policy) = (CipherSuite::Aes256Gcm, RekeyPolicy::default());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (reader, writer) = listener.accept().await.unwrap().0.into_split();
+
+        let manager = Arc::new(
+            MultiplexManager::new(
+                reader,
+                writer,
+                SessionCipher::server(keys(&server_secret), suite, policy),
+                Capabilities::DEFAULT,
+                DEFAULT_MAX_FRAME_SIZE,
+                timeouts,
+                WriteCoalescing::default(),
+            )
+            .unwrap(),
+        );
+        manager.start();
+        let cipher = SessionCipher::client(keys(&agent_secret), suite, policy);
+        let codec = FrameCodec::new(cipher, Capabilities::DEFAULT, DEFAULT_MAX_FRAME_SIZE);
+        (manager, Framed::new(agent, codec))
+    }
 
-        Ok(())
+    async fn next_packet(agent: &mut Agent) -> Packets {
+        let packet = tokio::time::timeout(Duration::from_secs(5), agent.next())
+            .await
+            .expect("no packet from the server")
+            .unwrap()
+            .unwrap();
+        from_packet_buf(packet).unwrap()
+    }
+
+    /// Open one stream more than the server queues for `accept_stream`, returning its ID
+    async fn overflow_accept_queue(agent: &mut Agent) -> StreamId {
+        // Agent streams have odd IDs
+        let refused = ACCEPT_QUEUE_CAPACITY as StreamId * 2 + 1;
+        for stream_id in (1..=refused).step_by(2) {
+            agent.feed(Packets::StreamOpen(StreamOpen { stream_id })).await.unwrap();
+        }
+        SinkExt::<Packets>::flush(agent).await.unwrap();
+        refused
     }
 
-    async fn send_packet(&self, buf: &[u8]) -> Result<(), NetworkError> {
-        let (encrypted_buf, nonce) = encrypt(&self.shared_secret, buf)?;
+    #[tokio::test]
+    async fn refuses_streams_past_the_accept_queue() {
+        let (manager, mut agent) = connection(Timeouts::default()).await;
+
+        let refused = overflow_accept_queue(&mut agent).await;
+
+        assert!(matches!(
+            next_packet(&mut agent).await,
+            Packets::StreamReset(StreamReset { stream_id, code: ResetCode::Refused })
+                if stream_id == refused
+        ));
+
+        // The receive loop still serves the queued streams
+        let data = StreamData {
+            stream_id: 1,
+            data: Bytes::from_static(b"ping"),
+        };
+        agent.send(Packets::StreamData(data)).await.unwrap();
+        let mut stream = manager.accept_stream().await.unwrap();
+        assert_eq!(stream.id(), 1);
+        assert_eq!(stream.receive().await.unwrap().unwrap(), &b"ping"[..]);
+    }
 
-        let len = encrypted_buf.len() as u32;
-        let total_size = 4 + nonce.len() + encrypted_buf.len();
-        let mut data = vec![0u8; total_size];
+    #[tokio::test]
+    async fn window_updates_arrive_while_streams_wait_to_be_accepted() {
+        let (manager, mut agent) = connection(Timeouts::default()).await;
+        overflow_accept_queue(&mut agent).await;
+
+        let stream = manager.open_stream().await.unwrap();
+        let stream_id = stream.id();
+        let window = INITIAL_STREAM_WINDOW as usize;
+        let sending = tokio::spawn(async move {
+            stream.send_bytes(vec![0; window + 10]).await?;
+            Ok::<_, NetworkError>(stream)
+        });
 
-        data[0..4].copy_from_slice(&len.to_be_bytes());
-        data[4..16].copy_from_slice(&nonce);
-        data[16..].copy_from_slice(&encrypted_buf);
+        let mut received = 0;
+        while received < window {
+            if let Packets::StreamData(data) = next_packet(&mut agent).await {
+                received += data.data.len();
+            }
+        }
+        let update = WindowUpdate {
+            stream_id,
+            increment: 10,
+        };
+        agent.send(Packets::WindowUpdate(update)).await.unwrap();
+
+        let sent = tokio::time::timeout(Duration::from_secs(5), sending).await;
+        assert!(matches!(sent, Ok(Ok(Ok(_)))));
+    }
 
-        let mut writer = self.writer.lock().await;
-        writer.write_all(&data).await?;
-        writer.flush().await?;
-        Ok(())
+    /// Wait for the server to close the connection, failing if it stays open for `limit`
+    async fn closed_within(agent: &mut Agent, limit: Duration) {
+        let closed = tokio::time::timeout(limit, async {
+            // Whatever the server sent before closing is skipped
+            while let Some(Ok(_)) = agent.next().await {}
+        })
+        .await;
+        assert!(closed.is_ok(), "connection still open after {limit:?}");
     }
 
-    async fn receive_packet(&self) -> Result<Vec<u8>, NetworkError> {
-        let mut reader = self.reader.lock().await;
+    #[tokio::test]
+    async fn silent_connections_are_reaped() {
+        let timeouts = Timeouts {
+            idle: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let (_manager, mut agent) = connection(timeouts).await;
 
-        let mut len_buf = [0u8; 4];
-        reader.read_exact(&mut len_buf).await?;
+        closed_within(&mut agent, Duration::from_secs(5)).await;
+    }
 
-        let len = u32::from_be_bytes(len_buf) as usize;
-        if len == 0 {
-            return Ok(Vec::new());
+    #[tokio::test]
+    async fn traffic_keeps_the_connection_open() {
+        let timeouts = Timeouts {
+            idle: Duration::from_millis(300),
+            ..Timeouts::default()
+        };
+        let (_manager, mut agent) = connection(timeouts).await;
+
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            agent.send(Packets::Heartbeat(Heartbeat::new())).await.unwrap();
         }
+        let packet = tokio::time::timeout(Duration::from_millis(100), agent.next()).await;
+        assert!(packet.is_err(), "connection closed while the agent was sending");
+    }
 
-        let mut nonce_buf = vec![0u8; 12];
-        reader.read_exact(&mut nonce_buf).await?;
-
-        let mut encrypted_buf = vec![0u8; len];
-        reader.read_exact(&mut encrypted_buf).await?;
+    #[tokio::test]
+    async fn partial_frames_time_out() {
+        let timeouts = Timeouts {
+            frame_read: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let (_manager, mut agent) = connection(timeouts).await;
 
-        let decrypted_data = decrypt(&self.shared_secret, &nonce_buf, &encrypted_buf)?;
+        // The start of a frame header, the rest never comes
+        agent.get_mut().write_all(&[FRAME_VERSION, 0, 0]).await.unwrap();
 
-        Ok(decrypted_data)
+        closed_within(&mut agent, Duration::from_secs(5)).await;
     }
 }
@@ -1,47 +1,107 @@
-use shared::{error::NetworkError, multiplexing::StreamId, packets::Packet};
+use bytes::Bytes;
+use shared::{
+    error::NetworkError,
+    multiplexing::{ResetCode, StreamId},
+    packets::Packet,
+};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 
 use super::multiplex::MultiplexManager;
 
+/// What the manager hands a stream: data, `Ok(None)` once the agent finished sending on it, or
+/// the code the stream was reset with
+pub type Incoming = Result<Option<Bytes>, ResetCode>;
+
+/// A stream of the connection, closed when dropped. Sending and receiving end on their own:
+/// after `finish` the stream still reads what the agent sends until it finishes too. A reset
+/// ends both at once.
 pub struct Stream {
     pub id: StreamId,
     pub manager: Arc<MultiplexManager>,
-    pub rx: mpsc::Receiver<Vec<u8>>,
+    pub rx: mpsc::UnboundedReceiver<Incoming>,
+    // The agent finished sending, or reset the stream, and everything before was read
+    ended: Option<Result<(), ResetCode>>,
 }
 
 impl Stream {
     pub fn new(
         id: StreamId,
         manager: Arc<MultiplexManager>,
-        rx: mpsc::Receiver<Vec<u8>>,
+        rx: mpsc::UnboundedReceiver<Incoming>,
     ) -> Self {
-        Self { id, manager, rx }
+        Self {
+            id,
+            manager,
+            rx,
+            ended: None,
+        }
     }
 
     pub fn id(&self) -> StreamId {
         self.id
     }
 
+    #[allow(dead_code)]
     pub async fn send<P: Packet>(&self, packet: P) -> Result<(), NetworkError> {
         let data = packet.serialize()?;
-        self.send_bytes(&data).await
+        self.send_bytes(data).await
     }
 
-    pub async fn send_bytes(&self, data: &[u8]) -> Result<(), NetworkError> {
-        self.manager.send_on_stream(self.id, data.to_vec()).await
+    /// Send `data` on the stream, `Bytes` and owned buffers are sent without being copied
+    pub async fn send_bytes(&self, data: impl Into<Bytes>) -> Result<(), NetworkError> {
+        self.manager.send_on_stream(self.id, data.into()).await
     }
 
-    pub async fn receive(&mut self) -> Result<Vec<u8>, NetworkError> {
-        self.rx
-            .recv()
-            .await
-            .ok_or(NetworkError::ChannelReceiveError)
+    /// Next data received on the stream, `None` once the agent finished sending on it and
+    /// `NetworkError::StreamReset` once either side reset it. The agent is granted more credit as it is read.
+    pub async fn receive(&mut self) -> Result<Option<Bytes>, NetworkError> {
+        if self.ended.is_none() {
+            // The channel only closes without the end of the stream when the connection is lost
+            match self
+                .rx
+                .recv()
+                .await
+                .ok_or(NetworkError::ChannelReceiveError)?
+            {
+                Ok(Some(data)) => {
+                    self.manager.release(self.id, data.len()).await?;
+                    return Ok(Some(data));
+                }
+                end => self.ended = Some(end.map(|_| ())),
+            }
+        }
+
+        match self.ended {
+            Some(Err(code)) => Err(NetworkError::StreamReset {
+                stream_id: self.id,
+                code,
+            }),
+            _ => Ok(None),
+        }
     }
 
+    /// Finish sending on the stream while still receiving, sending afterwards fails
+    pub async fn finish(&self) -> Result<(), NetworkError> {
+        self.manager.finish_stream(self.id).await
+    }
+
+    /// End both directions at once, telling the agent why with `code`
+    #[allow(dead_code)]
+    pub async fn reset(self, code: ResetCode) -> Result<(), NetworkError> {
+        self.manager.reset_stream(self.id, code).await
+    }
+
+    /// Stop sending and receiving: the stream is finished when the agent already finished,
+    /// otherwise it is reset with `ResetCode::Cancelled`. Dropping the stream does the same.
+    #[allow(dead_code)]
     pub async fn close(self) -> Result<(), NetworkError> {
-        // The stream will be automatically closed when dropped
-        // The MultiplexManager will detect this and clean up
-        Ok(())
+        self.manager.close_stream(self.id).await
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.manager.close_stream_in_background(self.id);
     }
 }
@@ -0,0 +1,305 @@
+use std::collections::VecDeque;
+use std::io::{self, IoSlice};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::misc::WriteCoalescing;
+
+/// Frames queued for the writer task of a connection before senders wait for it
+pub const WRITE_QUEUE_CAPACITY: usize = 256;
+
+/// Frames waiting to be written, handed to the socket together as one vectored write
+#[derive(Debug, Default)]
+struct FrameBatch {
+    frames: VecDeque<Bytes>,
+    remaining: usize,
+}
+
+impl FrameBatch {
+    fn push(&mut self, frame: Bytes) {
+        self.remaining += frame.len();
+        self.frames.push_back(frame);
+    }
+}
+
+impl Buf for FrameBatch {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.frames.front().map_or(&[], |frame| frame.as_ref())
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut count = 0;
+        for (slice, frame) in dst.iter_mut().zip(&self.frames) {
+            *slice = IoSlice::new(frame);
+            count += 1;
+        }
+        count
+    }
+
+    fn advance(&mut self, mut count: usize) {
+        assert!(
+            count <= self.remaining,
+            "advanced past the end of the batch"
+        );
+        self.remaining -= count;
+
+        while let Some(frame) = self.frames.front_mut() {
+            if count < frame.len() {
+                frame.advance(count);
+                return;
+            }
+            count -= frame.len();
+            self.frames.pop_front();
+        }
+    }
+}
+
+/// Write the frames of a connection until `closing` is cancelled or every sender is dropped.
+///
+/// After the first frame of a batch, frames keep being collected for up to `max_delay` or until
+/// `max_batch_size` bytes are pending, then the whole batch is written at once. Frames queued
+/// before closing are still written before the socket is shut down. A write taking more than
+/// `write_timeout`, like to an agent that stopped reading, fails with `ErrorKind::TimedOut`.
+pub async fn write_loop(
+    mut writer: impl AsyncWrite + Unpin,
+    mut frames: mpsc::Receiver<Bytes>,
+    coalescing: WriteCoalescing,
+    write_timeout: Duration,
+    closing: CancellationToken,
+) -> io::Result<()> {
+    let mut batch = FrameBatch::default();
+
+    loop {
+        let frame = tokio::select! {
+            frame = frames.recv() => frame,
+            _ = closing.cancelled() => None,
+        };
+        let Some(frame) = frame else { break };
+        batch.push(frame);
+
+        let linger = sleep(coalescing.max_delay);
+        tokio::pin!(linger);
+        while batch.remaining() < coalescing.max_batch_size {
+            // Frames already queued are taken before the budget is checked
+            tokio::select! {
+                biased;
+                frame = frames.recv() => match frame {
+                    Some(frame) => batch.push(frame),
+                    None => break,
+                },
+                _ = &mut linger => break,
+            }
+        }
+
+        tokio::select! {
+            result = write_batch(&mut writer, &mut batch, write_timeout) => result?,
+            // What is left of the batch is written below, the batch only advances past what was written
+            _ = closing.cancelled() => break,
+        }
+    }
+
+    frames.close();
+    while let Ok(frame) = frames.try_recv() {
+        batch.push(frame);
+    }
+    write_batch(&mut writer, &mut batch, write_timeout).await?;
+    writer.shutdown().await
+}
+
+async fn write_batch(
+    writer: &mut (impl AsyncWrite + Unpin),
+    batch: &mut FrameBatch,
+    write_timeout: Duration,
+) -> io::Result<()> {
+    timeout(write_timeout, writer.write_all_buf(batch))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "frames not written in time"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use super::*;
+    use tokio::{io::AsyncReadExt, task::JoinHandle};
+
+    /// Writer recording the bytes handed to each write call, accepting everything at once
+    #[derive(Clone, Default)]
+    struct RecordingWriter {
+        writes: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl RecordingWriter {
+        fn writes(&self) -> Vec<Vec<u8>> {
+            self.writes.lock().unwrap().clone()
+        }
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let write: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            let length = write.len();
+            self.writes.lock().unwrap().push(write);
+            Poll::Ready(Ok(length))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn spawn_writer(
+        writer: impl AsyncWrite + Unpin + Send + 'static,
+        coalescing: WriteCoalescing,
+        closing: CancellationToken,
+    ) -> (mpsc::Sender<Bytes>, JoinHandle<io::Result<()>>) {
+        spawn_writer_with_timeout(writer, coalescing, Duration::from_secs(30), closing)
+    }
+
+    fn spawn_writer_with_timeout(
+        writer: impl AsyncWrite + Unpin + Send + 'static,
+        coalescing: WriteCoalescing,
+        write_timeout: Duration,
+        closing: CancellationToken,
+    ) -> (mpsc::Sender<Bytes>, JoinHandle<io::Result<()>>) {
+        let (frames_tx, frames_rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+        let writing = write_loop(writer, frames_rx, coalescing, write_timeout, closing);
+        (frames_tx, tokio::spawn(writing))
+    }
+
+    #[test]
+    fn batch_advances_across_frames() {
+        let mut batch = FrameBatch::default();
+        batch.push(Bytes::from_static(b"first"));
+        batch.push(Bytes::from_static(b"second"));
+
+        let mut slices = [IoSlice::new(&[]); 4];
+        assert_eq!(batch.chunks_vectored(&mut slices), 2);
+        assert_eq!(batch.remaining(), 11);
+
+        batch.advance(7);
+        assert_eq!(batch.chunk(), b"cond");
+        batch.advance(4);
+        assert!(!batch.has_remaining());
+        assert_eq!(batch.chunk(), b"");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn frames_sent_within_the_delay_are_written_together() {
+        let writer = RecordingWriter::default();
+        let coalescing = WriteCoalescing {
+            max_delay: Duration::from_millis(10),
+            max_batch_size: 64 * 1024,
+        };
+        let (frames, handle) = spawn_writer(writer.clone(), coalescing, CancellationToken::new());
+
+        frames.send(Bytes::from_static(b"first")).await.unwrap();
+        sleep(Duration::from_millis(5)).await;
+        frames.send(Bytes::from_static(b"second")).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(writer.writes(), [b"firstsecond".to_vec()]);
+
+        // Past the delay the next frame starts a batch of its own
+        frames.send(Bytes::from_static(b"third")).await.unwrap();
+        drop(frames);
+        handle.await.unwrap().unwrap();
+        assert_eq!(writer.writes(), [b"firstsecond".to_vec(), b"third".to_vec()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_batches_are_written_without_waiting() {
+        let writer = RecordingWriter::default();
+        let coalescing = WriteCoalescing {
+            max_delay: Duration::from_secs(60 * 60),
+            max_batch_size: 10,
+        };
+        let closing = CancellationToken::new();
+        let (frames, handle) = spawn_writer(writer.clone(), coalescing, closing.clone());
+
+        frames.send(Bytes::from_static(b"12345")).await.unwrap();
+        frames.send(Bytes::from_static(b"67890")).await.unwrap();
+        frames.send(Bytes::from_static(b"next")).await.unwrap();
+        sleep(Duration::from_millis(1)).await;
+        assert_eq!(writer.writes(), [b"1234567890".to_vec()]);
+
+        // The frame past the limit waits for the next batch, written when closing
+        closing.cancel();
+        handle.await.unwrap().unwrap();
+        assert_eq!(writer.writes(), [b"1234567890".to_vec(), b"next".to_vec()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queued_frames_are_written_before_closing() {
+        let (writer, mut agent) = tokio::io::duplex(1024);
+        let coalescing = WriteCoalescing {
+            max_delay: Duration::from_secs(60 * 60),
+            max_batch_size: 64 * 1024,
+        };
+        let closing = CancellationToken::new();
+        let (frames, handle) = spawn_writer(writer, coalescing, closing.clone());
+
+        frames.send(Bytes::from_static(b"first")).await.unwrap();
+        frames.send(Bytes::from_static(b"second")).await.unwrap();
+        closing.cancel();
+        handle.await.unwrap().unwrap();
+
+        let mut received = Vec::new();
+        agent.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"firstsecond");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_writes_time_out() {
+        // The agent end is kept open but never read, the write stops once the pipe is full
+        let (writer, _agent) = tokio::io::duplex(64);
+        let write_timeout = Duration::from_secs(30);
+        let closing = CancellationToken::new();
+        let (frames, handle) = spawn_writer_with_timeout(
+            writer,
+            WriteCoalescing::default(),
+            write_timeout,
+            closing.clone(),
+        );
+
+        frames.send(Bytes::from(vec![0; 1024])).await.unwrap();
+        let started = tokio::time::Instant::now();
+        let error = handle.await.unwrap().unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= write_timeout);
+    }
+}
@@ -0,0 +1,152 @@
+//! Allocations and time spent per Stream Data frame, sealed then opened by the frame codec, along
+//! the zero-copy data path and along one allocating new buffers for every frame, like the path it
+//! replaced.
+//!
+//! Run with `cargo bench -p shared --bench frame_allocations`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use shared::{
+    encryption::{CipherSuite, KeySchedule, RekeyPolicy, SessionCipher},
+    framing::{DEFAULT_MAX_FRAME_SIZE, FrameCodec, FrameDecoder, FrameEncoder},
+    handshake::Capabilities,
+    key_exchange::{AgentKeyShare, ServerKeyShare},
+    packets::{Packet, Packets, StreamData, from_packet_buf, from_packet_bytes},
+};
+
+const FRAMES: usize = 10_000;
+const PAYLOAD_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+/// Counts every allocation, reallocations included, made through the system allocator
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+struct Measure {
+    allocations: usize,
+    bytes: usize,
+    elapsed: Duration,
+}
+
+fn measure(mut frame: impl FnMut()) -> Measure {
+    // Warm up so buffers reach their steady state size
+    for _ in 0..100 {
+        frame();
+    }
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        frame();
+    }
+    Measure {
+        elapsed: start.elapsed(),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        bytes: ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes,
+    }
+}
+
+/// Encoder of one end of a session and decoder of the other
+fn codec() -> (FrameEncoder, FrameDecoder) {
+    let agent_share = AgentKeyShare::generate(false);
+    let (_, shared_secret) =
+        ServerKeyShare::respond(agent_share.public_key(), None).expect("valid key share");
+    let schedule = KeySchedule::new(&shared_secret, None);
+    let transcript_hash = [0u8; 32];
+
+    let suite = CipherSuite::Aes256Gcm;
+    let policy = RekeyPolicy::default();
+    let client = SessionCipher::client(schedule.session_keys(&transcript_hash), suite, policy);
+    let server = SessionCipher::server(schedule.session_keys(&transcript_hash), suite, policy);
+    let codec = |cipher| FrameCodec::new(cipher, Capabilities::empty(), DEFAULT_MAX_FRAME_SIZE);
+    (codec(client).into_split().0, codec(server).into_split().1)
+}
+
+/// The payload copied into a `Vec` packet and serialized, sealed into a new buffer for every
+/// frame, then opened and decoded into new buffers again
+fn allocating_path(payload: &[u8]) -> Measure {
+    let (mut encoder, mut decoder) = codec();
+
+    measure(|| {
+        let packet = StreamData {
+            stream_id: 1,
+            data: Bytes::copy_from_slice(payload),
+        };
+        let mut frame = BytesMut::new();
+        encoder
+            .encode_frame(&packet.serialize().unwrap(), &mut frame)
+            .unwrap();
+
+        let packet = decoder.decode_frame(&mut frame).unwrap().unwrap();
+        black_box(from_packet_bytes(&packet).unwrap());
+    })
+}
+
+/// The packet serialized and sealed in place in a reused buffer, then opened in place and
+/// decoded with the payload sliced out of the received buffer
+fn zero_copy_path(payload: &Bytes) -> Measure {
+    let (mut encoder, mut decoder) = codec();
+    let mut buffer = BytesMut::with_capacity(2 * payload.len() + 64);
+
+    measure(|| {
+        let packet = Packets::StreamData(StreamData {
+            stream_id: 1,
+            data: payload.clone(),
+        });
+        encoder.encode_packet(&packet, &mut buffer).unwrap();
+
+        let packet = decoder.decode_frame(&mut buffer).unwrap().unwrap();
+        black_box(from_packet_buf(packet).unwrap());
+    })
+}
+
+fn main() {
+    println!(
+        "{:>9}  {:<10}  {:>12}  {:>12}  {:>10}",
+        "payload", "path", "allocs/frame", "bytes/frame", "ns/frame"
+    );
+
+    for size in PAYLOAD_SIZES {
+        let payload = Bytes::from(vec![0x42; size]);
+        for (path, result) in [
+            ("allocating", allocating_path(&payload)),
+            ("zero-copy", zero_copy_path(&payload)),
+        ] {
+            println!(
+                "{:>9}  {:<10}  {:>12.2}  {:>12.0}  {:>10.0}",
+                size,
+                path,
+                result.allocations as f64 / FRAMES as f64,
+                result.bytes as f64 / FRAMES as f64,
+                result.elapsed.as_nanos() as f64 / FRAMES as f64,
+            );
+        }
+    }
+}
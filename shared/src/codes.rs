@@ -0,0 +1,49 @@
+/// Encode a code enum as a single byte, decoding codes this build doesn't know as
+/// `Unknown(code)` instead of failing the whole packet, so new codes can be added later
+macro_rules! wire_code {
+    ($name:ident { $($variant:ident = $value:literal),* $(,)? }) => {
+        impl $name {
+            /// Byte the code is sent as
+            pub const fn to_u8(self) -> u8 {
+                match self {
+                    $($name::$variant => $value,)*
+                    $name::Unknown(code) => code,
+                }
+            }
+
+            pub const fn from_u8(code: u8) -> Self {
+                match code {
+                    $($value => $name::$variant,)*
+                    code => $name::Unknown(code),
+                }
+            }
+        }
+
+        impl bincode::Encode for $name {
+            fn encode<E: bincode::enc::Encoder>(
+                &self,
+                encoder: &mut E,
+            ) -> Result<(), bincode::error::EncodeError> {
+                bincode::Encode::encode(&self.to_u8(), encoder)
+            }
+        }
+
+        impl<Context> bincode::Decode<Context> for $name {
+            fn decode<D: bincode::de::Decoder<Context = Context>>(
+                decoder: &mut D,
+            ) -> Result<Self, bincode::error::DecodeError> {
+                Ok($name::from_u8(<u8 as bincode::Decode<Context>>::decode(decoder)?))
+            }
+        }
+
+        impl<'de, Context> bincode::BorrowDecode<'de, Context> for $name {
+            fn borrow_decode<D: bincode::de::BorrowDecoder<'de, Context = Context>>(
+                decoder: &mut D,
+            ) -> Result<Self, bincode::error::DecodeError> {
+                <Self as bincode::Decode<Context>>::decode(decoder)
+            }
+        }
+    };
+}
+
+pub(crate) use wire_code;
@@ -0,0 +1,152 @@
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::consts::{U12, U16};
+use aes_gcm::aead::{AeadCore, AeadInPlace, KeyInit};
+use bincode::{Decode, Encode};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use super::{EncryptionError, SecretKey};
+
+/// Size in bytes of the authentication tag appended to every ciphertext, for every suite
+pub const TAG_SIZE: usize = 16;
+
+/// Authenticated encryption algorithm used to protect the frames of a session.
+/// Frames are encrypted in place, the tag is returned separately so no buffer is allocated.
+pub trait AeadCipher: Send + Sync {
+    fn seal_in_place(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], EncryptionError>;
+    fn open_in_place(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> Result<(), EncryptionError>;
+}
+
+/// Cipher suites a session can be encrypted with, advertised by the agent and picked by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum CipherSuite {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl<C> AeadCipher for C
+where
+    C: AeadInPlace + AeadCore<NonceSize = U12, TagSize = U16> + Send + Sync,
+{
+    fn seal_in_place(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], EncryptionError> {
+        self.encrypt_in_place_detached(nonce.into(), aad, buffer)
+            .map(Into::into)
+            .map_err(|e| EncryptionError::FailedToEncrypt(e.to_string()))
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> Result<(), EncryptionError> {
+        self.decrypt_in_place_detached(nonce.into(), aad, buffer, tag.into())
+            .map_err(|e| EncryptionError::FailedToDecrypt(e.to_string()))
+    }
+}
+
+impl CipherSuite {
+    pub const ALL: [CipherSuite; 2] = [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305];
+
+    pub fn cipher(&self, key: &SecretKey) -> Box<dyn AeadCipher> {
+        let key = key.expose_secret();
+        match self {
+            CipherSuite::Aes256Gcm => Box::new(Aes256Gcm::new(key.into())),
+            CipherSuite::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305::new(key.into())),
+        }
+    }
+
+    /// Bit of the suite in the bitmask advertised during the handshake
+    pub fn bit(&self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => 0b01,
+            CipherSuite::ChaCha20Poly1305 => 0b10,
+        }
+    }
+
+    pub fn to_mask(suites: &[CipherSuite]) -> u8 {
+        suites.iter().fold(0, |mask, suite| mask | suite.bit())
+    }
+
+    pub fn from_mask(mask: u8) -> Vec<CipherSuite> {
+        Self::ALL.into_iter().filter(|suite| mask & suite.bit() != 0).collect()
+    }
+
+    /// First suite of `preference` also present in `mask`
+    pub fn negotiate(preference: &[CipherSuite], mask: u8) -> Option<CipherSuite> {
+        preference.iter().copied().find(|suite| mask & suite.bit() != 0)
+    }
+
+    /// Suites this machine runs fast, AES-GCM is only offered with hardware AES support
+    pub fn local_preference() -> Vec<CipherSuite> {
+        if has_aes_instructions() {
+            vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]
+        } else {
+            vec![CipherSuite::ChaCha20Poly1305]
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn has_aes_instructions() -> bool {
+    std::arch::is_x86_feature_detected!("aes")
+}
+
+#[cfg(target_arch = "aarch64")]
+fn has_aes_instructions() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn has_aes_instructions() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_round_trip() {
+        let mask = CipherSuite::to_mask(&CipherSuite::ALL);
+        assert_eq!(CipherSuite::from_mask(mask), CipherSuite::ALL);
+        assert_eq!(
+            CipherSuite::from_mask(CipherSuite::ChaCha20Poly1305.bit()),
+            [CipherSuite::ChaCha20Poly1305]
+        );
+        // Bits of suites we don't know are ignored
+        assert!(CipherSuite::from_mask(0b100).is_empty());
+    }
+
+    #[test]
+    fn negotiation_follows_our_preference() {
+        let preference = [CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+        let offered = CipherSuite::to_mask(&CipherSuite::ALL);
+
+        assert_eq!(
+            CipherSuite::negotiate(&preference, offered),
+            Some(CipherSuite::ChaCha20Poly1305)
+        );
+        assert_eq!(
+            CipherSuite::negotiate(&preference, CipherSuite::Aes256Gcm.bit()),
+            Some(CipherSuite::Aes256Gcm)
+        );
+        assert_eq!(CipherSuite::negotiate(&[CipherSuite::Aes256Gcm], 0b10), None);
+    }
+}
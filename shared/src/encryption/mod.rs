@@ -1,10 +1,20 @@
+mod cipher;
+mod schedule;
+mod secret;
+mod session;
+
 use aes_gcm::aead::Aead;
 use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
 use rand::Rng;
 use thiserror::Error;
 
-pub fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), EncryptionError> {
-    let cipher = Aes256Gcm::new(Key::<aes_gcm::aes::Aes256>::from_slice(key));
+pub use cipher::{AeadCipher, CipherSuite, TAG_SIZE};
+pub use schedule::{KeySchedule, SessionKeys, TrafficSecret};
+pub use secret::SecretKey;
+pub use session::{OpeningKey, RekeyPolicy, SealingKey, SessionCipher};
+
+pub fn encrypt(key: &SecretKey, plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), EncryptionError> {
+    let cipher = Aes256Gcm::new(Key::<aes_gcm::aes::Aes256>::from_slice(key.expose_secret()));
     let nonce: [u8; 12] = rand::rng().random();
     let ciphertext = match cipher.encrypt(Nonce::from_slice(&nonce), plaintext) {
         Ok(ct) => ct,
@@ -13,8 +23,8 @@ pub fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Encry
     Ok((ciphertext, nonce.to_vec()))
 }
 
-pub fn decrypt(key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
-    let cipher = Aes256Gcm::new(Key::<aes_gcm::aes::Aes256>::from_slice(key));
+pub fn decrypt(key: &SecretKey, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    let cipher = Aes256Gcm::new(Key::<aes_gcm::aes::Aes256>::from_slice(key.expose_secret()));
     match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
         Ok(decrypted) => Ok(decrypted),
         Err(e) => Err(EncryptionError::FailedToDecrypt(e.to_string())),
@@ -26,5 +36,9 @@ pub enum EncryptionError {
     #[error("Failed to encrypt: {0}")]
     FailedToEncrypt(String),
     #[error("Failed to decrypt: {0}")]
-    FailedToDecrypt(String)
+    FailedToDecrypt(String),
+    #[error("Frame {0} failed authentication, it was replayed, reordered or tampered with")]
+    OutOfSequence(u64),
+    #[error("Frame sequence numbers exhausted")]
+    SequenceExhausted,
 }
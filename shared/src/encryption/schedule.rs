@@ -0,0 +1,86 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::key_exchange::SharedSecret;
+
+use super::SecretKey;
+
+/// Prefix of every HKDF label, so keys are never reused by another protocol
+const LABEL_PREFIX: &[u8] = b"tcp-server-boilerplate ";
+
+/// Derives every key of a session from the handshake secrets.
+///
+/// The key exchange secret is extracted with HKDF-SHA256, salted with the pre-shared key or the
+/// resumption secret when one is used. Keys are then expanded with a label and the transcript hash, binding them to
+/// every message exchanged during the handshake.
+pub struct KeySchedule {
+    hkdf: Hkdf<Sha256>,
+}
+
+/// Secret from which the key of one direction of the connection is derived.
+/// Rekeying replaces it with `next`, which can't be used to recover the previous secret.
+pub struct TrafficSecret(SecretKey);
+
+/// Independent traffic secrets for each direction of a session
+pub struct SessionKeys {
+    pub client_to_server: TrafficSecret,
+    pub server_to_client: TrafficSecret,
+}
+
+impl KeySchedule {
+    pub fn new(shared_secret: &SharedSecret, salt: Option<&SecretKey>) -> Self {
+        let salt = salt.map(|salt| salt.expose_secret().as_slice());
+        KeySchedule {
+            hkdf: Hkdf::<Sha256>::new(salt, &shared_secret.to_bytes()),
+        }
+    }
+
+    /// Key protecting the handshake packets, bound to the transcript up to the server keys
+    pub fn handshake_key(&self, transcript_hash: &[u8; 32]) -> SecretKey {
+        self.expand(b"handshake", transcript_hash)
+    }
+
+    /// Traffic secrets of the session, bound to the whole handshake transcript
+    pub fn session_keys(&self, transcript_hash: &[u8; 32]) -> SessionKeys {
+        SessionKeys {
+            client_to_server: TrafficSecret(self.expand(b"c2s traffic", transcript_hash)),
+            server_to_client: TrafficSecret(self.expand(b"s2c traffic", transcript_hash)),
+        }
+    }
+
+    /// Secret of the session ticket issued after the handshake, salting the key schedule
+    /// of the connection resuming it
+    pub fn resumption_secret(&self, transcript_hash: &[u8; 32]) -> SecretKey {
+        self.expand(b"resumption", transcript_hash)
+    }
+
+    fn expand(&self, label: &[u8], context: &[u8]) -> SecretKey {
+        let mut output = SecretKey::from_bytes([0u8; 32]);
+        // 32 bytes is always a valid HKDF-SHA256 output length
+        self.hkdf
+            .expand_multi_info(&[LABEL_PREFIX, label, context], output.expose_secret_mut())
+            .expect("valid HKDF output length");
+        output
+    }
+}
+
+impl TrafficSecret {
+    /// AEAD key of this traffic secret
+    pub fn key(&self) -> SecretKey {
+        expand_secret(&self.0, b"key")
+    }
+
+    /// Traffic secret of the next key generation
+    pub fn next(&self) -> TrafficSecret {
+        TrafficSecret(expand_secret(&self.0, b"key update"))
+    }
+}
+
+fn expand_secret(secret: &SecretKey, label: &[u8]) -> SecretKey {
+    let hkdf = Hkdf::<Sha256>::from_prk(secret.expose_secret())
+        .expect("32 bytes is a valid PRK length");
+    let mut output = SecretKey::from_bytes([0u8; 32]);
+    hkdf.expand_multi_info(&[LABEL_PREFIX, label], output.expose_secret_mut())
+        .expect("valid HKDF output length");
+    output
+}
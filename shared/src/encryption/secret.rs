@@ -0,0 +1,33 @@
+use rand::Rng;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// 256 bits of key material, wiped from memory when dropped.
+///
+/// It deliberately has no `Debug` or `Display` impl so a secret can't end up in a log line,
+/// the raw bytes are only reachable through `expose_secret`.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        SecretKey(bytes)
+    }
+
+    /// Copy a 32 bytes slice, `None` if it has another length
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(SecretKey(bytes.try_into().ok()?))
+    }
+
+    pub fn random() -> Self {
+        SecretKey(rand::rng().random())
+    }
+
+    pub fn expose_secret(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Fill the key in place, so the secret is never copied out of it
+    pub(crate) fn expose_secret_mut(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+}
@@ -0,0 +1,335 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::{AeadCipher, CipherSuite, EncryptionError, SessionKeys, TAG_SIZE, TrafficSecret};
+
+/// Limits after which a sealing key is automatically replaced by its next generation
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    pub max_frames: u64,
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+/// Encrypts the frames sent in one direction of a session.
+///
+/// The nonce is the frame sequence number, never transmitted: both peers count the frames
+/// they exchange, so a replayed, dropped or reordered frame fails to decrypt.
+pub struct SealingKey {
+    secret: TrafficSecret,
+    suite: CipherSuite,
+    cipher: Box<dyn AeadCipher>,
+    sequence: u64,
+    generation: u64,
+    bytes_sealed: u64,
+    created_at: Instant,
+    policy: RekeyPolicy,
+}
+
+/// Decrypts the frames received in one direction of a session, see `SealingKey`
+pub struct OpeningKey {
+    secret: TrafficSecret,
+    suite: CipherSuite,
+    cipher: Box<dyn AeadCipher>,
+    sequence: u64,
+    generation: u64,
+}
+
+/// Sealing and opening keys of one peer of a session
+pub struct SessionCipher {
+    sealing: SealingKey,
+    opening: OpeningKey,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_frames: 1 << 24,
+            max_bytes: 1 << 30,
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl SealingKey {
+    pub fn new(secret: TrafficSecret, suite: CipherSuite, policy: RekeyPolicy) -> Self {
+        SealingKey {
+            cipher: suite.cipher(&secret.key()),
+            secret,
+            suite,
+            sequence: 0,
+            generation: 0,
+            bytes_sealed: 0,
+            created_at: Instant::now(),
+            policy,
+        }
+    }
+
+    /// Encrypt `plaintext`, authenticating `aad` along with it
+    pub fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let mut ciphertext = Vec::with_capacity(plaintext.len() + TAG_SIZE);
+        ciphertext.extend_from_slice(plaintext);
+        let tag = self.seal_in_place(aad, &mut ciphertext)?;
+        ciphertext.extend_from_slice(&tag);
+        Ok(ciphertext)
+    }
+
+    /// Encrypt `buffer` in place, authenticating `aad` along with it, and return the tag
+    pub fn seal_in_place(
+        &mut self,
+        aad: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], EncryptionError> {
+        let nonce = sequence_nonce(self.sequence)?;
+        let tag = self.cipher.seal_in_place(&nonce, aad, buffer)?;
+        self.sequence += 1;
+        self.bytes_sealed += (buffer.len() + TAG_SIZE) as u64;
+        Ok(tag)
+    }
+
+    /// Whether the key exceeded its rekey policy and should be updated
+    pub fn needs_update(&self) -> bool {
+        self.sequence >= self.policy.max_frames
+            || self.bytes_sealed >= self.policy.max_bytes
+            || self.created_at.elapsed() >= self.policy.max_age
+    }
+
+    /// Replace the key with its next generation, the peer must update its opening key
+    /// after the last frame sealed with the current one
+    pub fn update(&mut self) {
+        self.secret = self.secret.next();
+        self.cipher = self.suite.cipher(&self.secret.key());
+        self.sequence = 0;
+        self.generation += 1;
+        self.bytes_sealed = 0;
+        self.created_at = Instant::now();
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl OpeningKey {
+    pub fn new(secret: TrafficSecret, suite: CipherSuite) -> Self {
+        OpeningKey {
+            cipher: suite.cipher(&secret.key()),
+            secret,
+            suite,
+            sequence: 0,
+            generation: 0,
+        }
+    }
+
+    /// Decrypt `ciphertext`, failing if it or `aad` was tampered with
+    pub fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let Some(length) = ciphertext.len().checked_sub(TAG_SIZE) else {
+            return Err(EncryptionError::FailedToDecrypt("ciphertext shorter than its tag".to_string()));
+        };
+        let (ciphertext, tag) = ciphertext.split_at(length);
+        let mut plaintext = ciphertext.to_vec();
+        self.open_in_place(aad, &mut plaintext, tag.try_into().expect("slice of the tag size"))?;
+        Ok(plaintext)
+    }
+
+    /// Decrypt `buffer` in place, failing if it, `aad` or `tag` was tampered with
+    pub fn open_in_place(
+        &mut self,
+        aad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> Result<(), EncryptionError> {
+        let nonce = sequence_nonce(self.sequence)?;
+        self.cipher
+            .open_in_place(&nonce, aad, buffer, tag)
+            .map_err(|_| EncryptionError::OutOfSequence(self.sequence))?;
+        self.sequence += 1;
+        Ok(())
+    }
+
+    /// Replace the key with its next generation, following a key update of the peer
+    pub fn update(&mut self) {
+        self.secret = self.secret.next();
+        self.cipher = self.suite.cipher(&self.secret.key());
+        self.sequence = 0;
+        self.generation += 1;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl SessionCipher {
+    /// Cipher of the agent, sealing with the client to server key
+    pub fn client(keys: SessionKeys, suite: CipherSuite, policy: RekeyPolicy) -> Self {
+        SessionCipher {
+            sealing: SealingKey::new(keys.client_to_server, suite, policy),
+            opening: OpeningKey::new(keys.server_to_client, suite),
+        }
+    }
+
+    /// Cipher of the server, sealing with the server to client key
+    pub fn server(keys: SessionKeys, suite: CipherSuite, policy: RekeyPolicy) -> Self {
+        SessionCipher {
+            sealing: SealingKey::new(keys.server_to_client, suite, policy),
+            opening: OpeningKey::new(keys.client_to_server, suite),
+        }
+    }
+
+    pub fn sealing_key(&mut self) -> &mut SealingKey {
+        &mut self.sealing
+    }
+
+    pub fn opening_key(&mut self) -> &mut OpeningKey {
+        &mut self.opening
+    }
+
+    /// Split the cipher so each direction can be used from its own task
+    pub fn into_split(self) -> (SealingKey, OpeningKey) {
+        (self.sealing, self.opening)
+    }
+}
+
+impl fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SealingKey")
+            .field("suite", &self.suite)
+            .field("sequence", &self.sequence)
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for OpeningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpeningKey")
+            .field("suite", &self.suite)
+            .field("sequence", &self.sequence)
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for SessionCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCipher")
+            .field("sealing", &self.sealing)
+            .field("opening", &self.opening)
+            .finish()
+    }
+}
+
+/// 96 bits nonce made of 4 zero bytes followed by the big-endian sequence number
+fn sequence_nonce(sequence: u64) -> Result<[u8; 12], EncryptionError> {
+    if sequence == u64::MAX {
+        return Err(EncryptionError::SequenceExhausted);
+    }
+
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    Ok(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::KeySchedule;
+    use crate::key_exchange::{AgentKeyShare, ServerKeyShare};
+
+    /// Session keys of the agent and the server, from a fresh key exchange
+    fn session_keys() -> (SessionKeys, SessionKeys) {
+        let agent = AgentKeyShare::generate(false);
+        let (server, shared_secret) = ServerKeyShare::respond(agent.public_key(), None).unwrap();
+        let agent_secret = agent.finish(server.public_key, None).unwrap();
+        (
+            KeySchedule::new(&agent_secret, None).session_keys(&[7; 32]),
+            KeySchedule::new(&shared_secret, None).session_keys(&[7; 32]),
+        )
+    }
+
+    /// Sealing key of the agent and opening key of the server, sharing fresh session keys
+    fn keys(policy: RekeyPolicy) -> (SealingKey, OpeningKey) {
+        let (agent_keys, server_keys) = session_keys();
+        let suite = CipherSuite::Aes256Gcm;
+        let (sealing, _) = SessionCipher::client(agent_keys, suite, policy).into_split();
+        let (_, opening) = SessionCipher::server(server_keys, suite, policy).into_split();
+        (sealing, opening)
+    }
+
+    #[test]
+    fn policy_triggers_after_max_frames() {
+        let policy = RekeyPolicy {
+            max_frames: 2,
+            ..RekeyPolicy::default()
+        };
+        let (mut sealing, _) = keys(policy);
+
+        sealing.seal(b"", b"first").unwrap();
+        assert!(!sealing.needs_update());
+        sealing.seal(b"", b"second").unwrap();
+        assert!(sealing.needs_update());
+    }
+
+    #[test]
+    fn policy_triggers_after_max_bytes() {
+        let policy = RekeyPolicy {
+            max_bytes: 64,
+            ..RekeyPolicy::default()
+        };
+        let (mut sealing, _) = keys(policy);
+
+        // The tag counts toward the budget
+        sealing.seal(b"", &[0; 64 - TAG_SIZE - 1]).unwrap();
+        assert!(!sealing.needs_update());
+        sealing.seal(b"", &[0]).unwrap();
+        assert!(sealing.needs_update());
+    }
+
+    #[test]
+    fn policy_triggers_after_max_age() {
+        let policy = RekeyPolicy {
+            max_age: Duration::ZERO,
+            ..RekeyPolicy::default()
+        };
+        let (sealing, _) = keys(policy);
+        assert!(sealing.needs_update());
+
+        let (sealing, _) = keys(RekeyPolicy::default());
+        assert!(!sealing.needs_update());
+    }
+
+    #[test]
+    fn update_starts_a_fresh_budget_with_the_next_key() {
+        let policy = RekeyPolicy {
+            max_frames: 1,
+            ..RekeyPolicy::default()
+        };
+        let (mut sealing, mut opening) = keys(policy);
+
+        let first = sealing.seal(b"aad", b"first").unwrap();
+        assert!(sealing.needs_update());
+        sealing.update();
+        assert!(!sealing.needs_update());
+        assert_eq!(sealing.generation(), 1);
+        let second = sealing.seal(b"aad", b"second").unwrap();
+
+        assert_eq!(opening.open(b"aad", &first).unwrap(), b"first");
+        // Still on the previous generation, the next key's frame doesn't open
+        assert!(opening.open(b"aad", &second).is_err());
+        opening.update();
+        assert_eq!(opening.generation(), 1);
+        assert_eq!(opening.open(b"aad", &second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn frames_of_another_suite_are_rejected() {
+        let (agent_keys, server_keys) = session_keys();
+        let policy = RekeyPolicy::default();
+        let mut agent = SessionCipher::client(agent_keys, CipherSuite::Aes256Gcm, policy);
+        let mut server = SessionCipher::server(server_keys, CipherSuite::ChaCha20Poly1305, policy);
+
+        let frame = agent.sealing_key().seal(b"aad", b"suite").unwrap();
+        assert!(server.opening_key().open(b"aad", &frame).is_err());
+    }
+}
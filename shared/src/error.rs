@@ -1,7 +1,9 @@
 use std::io;
+use std::ops::RangeInclusive;
+use std::time::Duration;
 use thiserror::Error;
 
-use crate::{encryption::EncryptionError, packets::PacketError};
+use crate::{encryption::{CipherSuite, EncryptionError}, handshake::{Capabilities, HandshakeMode, RejectCode}, multiplexing::{CONTROL_STREAM_ID, ResetCode}, packets::PacketError};
 
 #[derive(Debug, Error)]
 pub enum NetworkError {
@@ -17,6 +19,36 @@ pub enum NetworkError {
     ConvertError,
     #[error("Encryption token didn't match, got {got}, expected {expected}")]
     TokenDontMatch { expected: u64, got: u64 },
+    #[error("Server authentication failed: {0}")]
+    ServerAuthenticationFailed(String),
+    #[error("Agent authentication failed: {0}")]
+    AgentAuthenticationFailed(String),
+    #[error("Handshake mode mismatch, requested {requested:?}, got {got:?}")]
+    HandshakeModeMismatch { requested: HandshakeMode, got: HandshakeMode },
+    #[error("Pre-shared key mismatch")]
+    PreSharedKeyMismatch,
+    #[error("Unsupported protocol version, offered {offered:?}, supported {supported:?}")]
+    UnsupportedProtocolVersion { offered: RangeInclusive<u16>, supported: RangeInclusive<u16> },
+    #[error("Handshake rejected ({code:?}): {message}")]
+    HandshakeRejected { code: RejectCode, message: String },
+    #[error("Not negotiated for this connection: {0:?}")]
+    CapabilityNotNegotiated(Capabilities),
+    #[error("Invalid key share: {0}")]
+    InvalidKeyShare(String),
+    #[error("No cipher suite in common, offered {offered:?}, got {got:?}")]
+    NoCommonCipherSuite { offered: Vec<CipherSuite>, got: CipherSuite },
+    #[error("Handshake not completed within {0:?}")]
+    HandshakeTimeout(Duration),
+    #[error("Frame not received in full within {0:?} of its first byte")]
+    FrameReadTimeout(Duration),
+    #[error("No frame received for {0:?}")]
+    IdleTimeout(Duration),
+    #[error("Frame of {length} bytes exceeds the maximum frame size of {max} bytes")]
+    FrameTooLarge { length: usize, max: u32 },
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+    #[error("Failed to compress packet: {0}")]
+    CompressionError(String),
     #[error("Failed to lock mutex")]
     LockError,
     #[error("Stream {0} not found")]
@@ -25,8 +57,95 @@ pub enum NetworkError {
     StreamAlreadyExists(u32),
     #[error("Stream {0} closed")]
     StreamClosed(u32),
+    #[error("Stream {stream_id} reset ({code:?})")]
+    StreamReset { stream_id: u32, code: ResetCode },
+    #[error("Stream {0} opened by the peer outside of its stream IDs")]
+    InvalidStreamId(u32),
+    #[error("Stream {0} opened by the peer below an ID it already used")]
+    StreamIdReused(u32),
+    #[error("Every stream ID of this connection was used")]
+    StreamIdsExhausted,
+    #[error("Flow control window of stream {0} exceeded or overflowed")]
+    FlowControlViolation(u32),
     #[error("Failed to send on channel")]
     ChannelSendError,
     #[error("Failed to receive on channel")]
     ChannelReceiveError,
 }
+
+impl NetworkError {
+    /// Whether connecting again may succeed, `false` when the peers can't agree
+    /// or the server refused the agent for good. Agents still retry, only less often.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            NetworkError::HandshakeRejected { code, .. } => code.is_retryable(),
+            NetworkError::UnsupportedProtocolVersion { .. }
+            | NetworkError::HandshakeModeMismatch { .. }
+            | NetworkError::NoCommonCipherSuite { .. }
+            | NetworkError::CapabilityNotNegotiated(_)
+            | NetworkError::PreSharedKeyMismatch
+            | NetworkError::ServerAuthenticationFailed(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Code to reset the stream the peer broke the protocol on when the error is confined to that
+    /// stream, `None` when it is fatal to the whole connection
+    pub fn reset_code(&self) -> Option<ResetCode> {
+        match self {
+            // A Stream Open in our own half of the IDs names one of our streams, which the peer
+            // must not be able to reset, so `InvalidStreamId` is fatal
+            NetworkError::StreamAlreadyExists(_)
+            | NetworkError::StreamIdReused(_)
+            | NetworkError::PacketError(_)
+            | NetworkError::UnexpectedPacket => Some(ResetCode::ProtocolError),
+            NetworkError::StreamClosed(_) => Some(ResetCode::StreamClosed),
+            // The connection window is shared by every stream
+            NetworkError::FlowControlViolation(stream_id) if *stream_id != CONTROL_STREAM_ID => {
+                Some(ResetCode::FlowControlError)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_errors_reset_the_stream() {
+        let decoding = PacketError::DecodingError("truncated".into());
+
+        assert_eq!(NetworkError::StreamAlreadyExists(3).reset_code(), Some(ResetCode::ProtocolError));
+        assert_eq!(NetworkError::StreamIdReused(3).reset_code(), Some(ResetCode::ProtocolError));
+        assert_eq!(NetworkError::PacketError(decoding).reset_code(), Some(ResetCode::ProtocolError));
+        assert_eq!(NetworkError::UnexpectedPacket.reset_code(), Some(ResetCode::ProtocolError));
+        assert_eq!(NetworkError::StreamClosed(3).reset_code(), Some(ResetCode::StreamClosed));
+        assert_eq!(NetworkError::FlowControlViolation(3).reset_code(), Some(ResetCode::FlowControlError));
+    }
+
+    #[test]
+    fn connection_errors_have_no_reset_code() {
+        assert_eq!(NetworkError::InvalidStreamId(2).reset_code(), None);
+        assert_eq!(NetworkError::FlowControlViolation(CONTROL_STREAM_ID).reset_code(), None);
+        assert_eq!(NetworkError::StreamIdsExhausted.reset_code(), None);
+        assert_eq!(NetworkError::InvalidFrame("bad version".into()).reset_code(), None);
+        assert_eq!(NetworkError::FrameTooLarge { length: 2, max: 1 }.reset_code(), None);
+    }
+
+    #[test]
+    fn disagreements_are_not_retryable() {
+        let rejected = |code| NetworkError::HandshakeRejected {
+            code,
+            message: String::new(),
+        };
+
+        assert!(!NetworkError::PreSharedKeyMismatch.is_retryable());
+        assert!(!NetworkError::ServerAuthenticationFailed("bad signature".into()).is_retryable());
+        assert!(!rejected(RejectCode::Banned).is_retryable());
+        assert!(rejected(RejectCode::Overloaded).is_retryable());
+        assert!(NetworkError::HandshakeTimeout(Duration::from_secs(10)).is_retryable());
+        assert!(NetworkError::IoError(io::ErrorKind::ConnectionRefused.into()).is_retryable());
+    }
+}
@@ -0,0 +1,588 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::encryption::{OpeningKey, SealingKey, SessionCipher, TAG_SIZE};
+use crate::error::NetworkError;
+use crate::handshake::Capabilities;
+use crate::packets::{KeyUpdate, Packet, Packets, StreamData};
+
+use super::compression::{COMPRESSION_THRESHOLD, Compression, Compressor, Decompressor};
+use super::{FLAG_COMPRESSED, FrameHeader, HEADER_SIZE};
+
+/// Encrypts packets into frames, serialized and encrypted in place in the output buffer.
+///
+/// The sealing key is switched to its next generation right after a Key Update is encoded, and
+/// when `KEY_UPDATE` was negotiated a Key Update is appended on its own once the key is due for one.
+/// Stream Data packets of at least `COMPRESSION_THRESHOLD` bytes are compressed before encryption
+/// when a `Compression` was negotiated, unless that doesn't make them smaller.
+#[derive(Debug)]
+pub struct FrameEncoder {
+    sealing_key: SealingKey,
+    key_updates: bool,
+    max_frame_size: u32,
+    compressor: Option<Compressor>,
+    // Compressed packets are written here before replacing the serialized ones
+    scratch: BytesMut,
+}
+
+/// Decrypts frames into packets, buffering partial frames until they are received in full.
+/// Packets are decrypted in place and share the buffer they were received in.
+///
+/// The opening key is switched to its next generation right after a Key Update is decoded, the
+/// caller still receives the packet to answer it. Compressed packets are decompressed into a
+/// buffer of their own.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    opening_key: OpeningKey,
+    max_frame_size: u32,
+    decompressor: Option<Decompressor>,
+}
+
+/// Both halves of the frame format of a connection, to use with a single `Framed`
+#[derive(Debug)]
+pub struct FrameCodec {
+    encoder: FrameEncoder,
+    decoder: FrameDecoder,
+}
+
+impl FrameEncoder {
+    pub fn new(sealing_key: SealingKey, capabilities: Capabilities, max_frame_size: u32) -> Self {
+        FrameEncoder {
+            sealing_key,
+            key_updates: capabilities.contains(Capabilities::KEY_UPDATE),
+            max_frame_size,
+            compressor: Compression::negotiate(capabilities).map(Compressor::new),
+            scratch: BytesMut::new(),
+        }
+    }
+
+    /// Append the frame of `packet` to `dst`, serializing and encrypting it in place
+    pub fn encode_packet(&mut self, packet: &Packets, dst: &mut BytesMut) -> Result<(), NetworkError> {
+        self.seal_into(dst, |dst| Ok(packet.serialize_into(dst)?))?;
+        self.after_frame(matches!(packet, Packets::KeyUpdate(_)), dst)
+    }
+
+    /// Append the frame of the already serialized `packet` to `dst`
+    pub fn encode_frame(&mut self, packet: &[u8], dst: &mut BytesMut) -> Result<(), NetworkError> {
+        self.seal_into(dst, |dst| {
+            dst.extend_from_slice(packet);
+            Ok(())
+        })?;
+        self.after_frame(is_key_update(packet), dst)
+    }
+
+    /// Generation of the sealing key the next frame is encrypted with
+    pub fn generation(&self) -> u64 {
+        self.sealing_key.generation()
+    }
+
+    /// Reserve the header, let `write` append the packet after it, compress it when worth it, then
+    /// encrypt the packet in place and append its tag. Nothing is left in `dst` on error.
+    fn seal_into(
+        &mut self,
+        dst: &mut BytesMut,
+        write: impl FnOnce(&mut BytesMut) -> Result<(), NetworkError>,
+    ) -> Result<(), NetworkError> {
+        let start = dst.len();
+        dst.put_bytes(0, HEADER_SIZE);
+
+        let result = write(dst).and_then(|()| {
+            let packet_start = start + HEADER_SIZE;
+            // Checked before compression, the peer bounds decompressed packets by the same limit
+            let length = dst.len() - packet_start + TAG_SIZE;
+            if length > self.max_frame_size as usize {
+                return Err(NetworkError::FrameTooLarge {
+                    length,
+                    max: self.max_frame_size,
+                });
+            }
+
+            let flags = self.compress(dst, packet_start)?;
+            let length = dst.len() - packet_start + TAG_SIZE;
+            let header = FrameHeader::new(flags, length as u32).to_bytes();
+            dst[start..packet_start].copy_from_slice(&header);
+            Ok(self
+                .sealing_key
+                .seal_in_place(&header, &mut dst[packet_start..])?)
+        });
+
+        match result {
+            Ok(tag) => {
+                dst.extend_from_slice(&tag);
+                Ok(())
+            }
+            Err(e) => {
+                dst.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
+    /// Compress the packet written from `packet_start` when it is a large enough Stream Data
+    /// packet, returning the flags of its frame. Incompressible packets are left as they are.
+    fn compress(&mut self, dst: &mut BytesMut, packet_start: usize) -> Result<u8, NetworkError> {
+        let Some(compressor) = &mut self.compressor else {
+            return Ok(0);
+        };
+        let packet = &dst[packet_start..];
+        if packet.len() < COMPRESSION_THRESHOLD || packet[0] != StreamData::packet_code() {
+            return Ok(0);
+        }
+
+        self.scratch.clear();
+        compressor.compress_into(packet, &mut self.scratch)?;
+        if self.scratch.len() >= packet.len() {
+            return Ok(0);
+        }
+        dst.truncate(packet_start);
+        dst.extend_from_slice(&self.scratch);
+        Ok(FLAG_COMPRESSED)
+    }
+
+    /// Switch keys after a key update, or send one when the key is due for it
+    fn after_frame(&mut self, key_update: bool, dst: &mut BytesMut) -> Result<(), NetworkError> {
+        if key_update {
+            self.sealing_key.update();
+        } else if self.key_updates && self.sealing_key.needs_update() {
+            self.seal_into(dst, |dst| Ok(KeyUpdate::new(true).serialize_into(dst)?))?;
+            self.sealing_key.update();
+        }
+        Ok(())
+    }
+}
+
+impl FrameDecoder {
+    pub fn new(opening_key: OpeningKey, capabilities: Capabilities, max_frame_size: u32) -> Self {
+        FrameDecoder {
+            opening_key,
+            max_frame_size,
+            decompressor: Compression::negotiate(capabilities).map(Decompressor::new),
+        }
+    }
+
+    /// Take the next complete frame out of `src` and decrypt it in place,
+    /// `None` until the whole frame was received
+    pub fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, NetworkError> {
+        let Some(header) = src.get(..HEADER_SIZE) else {
+            return Ok(None);
+        };
+        // The length is checked against the maximum frame size before waiting for the frame
+        let header = FrameHeader::from_bytes(
+            header.try_into().expect("slice of the header size"),
+            self.max_frame_size,
+        )?;
+
+        let length = HEADER_SIZE + header.length as usize;
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+
+        let mut packet = src.split_to(length);
+        packet.advance(HEADER_SIZE);
+        // The header was checked to be at least as long as the tag
+        let tag_start = packet.len() - TAG_SIZE;
+        let tag: [u8; TAG_SIZE] = packet[tag_start..].try_into().expect("slice of the tag size");
+        packet.truncate(tag_start);
+        self.opening_key.open_in_place(&header.to_bytes(), &mut packet, &tag)?;
+
+        if header.flags & FLAG_COMPRESSED != 0 {
+            let Some(decompressor) = &mut self.decompressor else {
+                return Err(NetworkError::InvalidFrame(
+                    "compressed frame without a negotiated compression".to_string(),
+                ));
+            };
+            packet = decompressor.decompress(&packet, self.max_frame_size as usize - TAG_SIZE)?;
+        }
+
+        // Every frame after a key update is sealed with the next generation
+        if is_key_update(&packet) {
+            self.opening_key.update();
+        }
+        Ok(Some(packet.freeze()))
+    }
+
+    /// Generation of the opening key the next frame is decrypted with
+    pub fn generation(&self) -> u64 {
+        self.opening_key.generation()
+    }
+}
+
+impl FrameCodec {
+    pub fn new(cipher: SessionCipher, capabilities: Capabilities, max_frame_size: u32) -> Self {
+        let (sealing_key, opening_key) = cipher.into_split();
+        FrameCodec {
+            encoder: FrameEncoder::new(sealing_key, capabilities, max_frame_size),
+            decoder: FrameDecoder::new(opening_key, capabilities, max_frame_size),
+        }
+    }
+
+    /// Split the codec to encode and decode from separate halves of the connection
+    pub fn into_split(self) -> (FrameEncoder, FrameDecoder) {
+        (self.encoder, self.decoder)
+    }
+}
+
+fn is_key_update(packet: &[u8]) -> bool {
+    packet.first() == Some(&KeyUpdate::packet_code())
+}
+
+#[cfg(feature = "codec")]
+mod tokio_codec {
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{FrameCodec, FrameDecoder, FrameEncoder};
+    use crate::error::NetworkError;
+    use crate::packets::Packets;
+
+    impl Encoder<Packets> for FrameEncoder {
+        type Error = NetworkError;
+
+        fn encode(&mut self, packet: Packets, dst: &mut BytesMut) -> Result<(), NetworkError> {
+            self.encode_packet(&packet, dst)
+        }
+    }
+
+    impl Encoder<&[u8]> for FrameEncoder {
+        type Error = NetworkError;
+
+        fn encode(&mut self, packet: &[u8], dst: &mut BytesMut) -> Result<(), NetworkError> {
+            self.encode_frame(packet, dst)
+        }
+    }
+
+    impl Decoder for FrameDecoder {
+        type Item = Bytes;
+        type Error = NetworkError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, NetworkError> {
+            self.decode_frame(src)
+        }
+    }
+
+    impl Encoder<Packets> for FrameCodec {
+        type Error = NetworkError;
+
+        fn encode(&mut self, packet: Packets, dst: &mut BytesMut) -> Result<(), NetworkError> {
+            self.encoder.encode_packet(&packet, dst)
+        }
+    }
+
+    impl Encoder<&[u8]> for FrameCodec {
+        type Error = NetworkError;
+
+        fn encode(&mut self, packet: &[u8], dst: &mut BytesMut) -> Result<(), NetworkError> {
+            self.encoder.encode_frame(packet, dst)
+        }
+    }
+
+    impl Decoder for FrameCodec {
+        type Item = Bytes;
+        type Error = NetworkError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>, NetworkError> {
+            self.decoder.decode_frame(src)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{CipherSuite, KeySchedule, RekeyPolicy};
+    use crate::framing::{FRAME_VERSION, MIN_FRAME_SIZE};
+    use crate::key_exchange::{AgentKeyShare, ServerKeyShare};
+    use crate::packets::{StreamClose, from_packet_buf};
+
+    const MAX: u32 = MIN_FRAME_SIZE;
+
+    /// Codecs of the agent and the server, sharing fresh session keys
+    fn codecs(
+        capabilities: Capabilities,
+        suite: CipherSuite,
+        policy: RekeyPolicy,
+    ) -> (FrameCodec, FrameCodec) {
+        let agent = AgentKeyShare::generate(false);
+        let (server, shared_secret) = ServerKeyShare::respond(agent.public_key(), None).unwrap();
+        let agent_secret = agent.finish(server.public_key, None).unwrap();
+        let transcript_hash = [7; 32];
+        let agent_keys = KeySchedule::new(&agent_secret, None).session_keys(&transcript_hash);
+        let server_keys = KeySchedule::new(&shared_secret, None).session_keys(&transcript_hash);
+
+        let agent = SessionCipher::client(agent_keys, suite, policy);
+        let server = SessionCipher::server(server_keys, suite, policy);
+        (
+            FrameCodec::new(agent, capabilities, MAX),
+            FrameCodec::new(server, capabilities, MAX),
+        )
+    }
+
+    /// Encoder of the agent and decoder of the server, sharing fresh session keys
+    fn codec_pair(capabilities: Capabilities) -> (FrameEncoder, FrameDecoder) {
+        let (agent, server) = codecs(capabilities, CipherSuite::Aes256Gcm, RekeyPolicy::default());
+        let (encoder, _) = agent.into_split();
+        let (_, decoder) = server.into_split();
+        (encoder, decoder)
+    }
+
+    fn key_update(decoded: Bytes) -> KeyUpdate {
+        match from_packet_buf(decoded).unwrap() {
+            Packets::KeyUpdate(packet) => packet,
+            other => panic!("expected Key Update, got {other:?}"),
+        }
+    }
+
+    fn frame(encoder: &mut FrameEncoder, data: &'static [u8]) -> BytesMut {
+        let packet = Packets::StreamData(StreamData {
+            stream_id: 1,
+            data: Bytes::from_static(data),
+        });
+        let mut frame = BytesMut::new();
+        encoder.encode_packet(&packet, &mut frame).unwrap();
+        frame
+    }
+
+    fn stream_data(packet: Bytes) -> Bytes {
+        match from_packet_buf(packet).unwrap() {
+            Packets::StreamData(packet) => packet.data,
+            other => panic!("expected Stream Data, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_in_order() {
+        let (mut encoder, mut decoder) = codec_pair(Capabilities::empty());
+        let mut received = frame(&mut encoder, b"first");
+        received.extend_from_slice(&frame(&mut encoder, b"second"));
+
+        let first = decoder.decode_frame(&mut received).unwrap().unwrap();
+        let second = decoder.decode_frame(&mut received).unwrap().unwrap();
+
+        assert_eq!(stream_data(first), &b"first"[..]);
+        assert_eq!(stream_data(second), &b"second"[..]);
+        assert!(decoder.decode_frame(&mut received).unwrap().is_none());
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        let (mut encoder, mut decoder) = codec_pair(Capabilities::empty());
+        let frame = frame(&mut encoder, b"partial");
+
+        let mut received = BytesMut::from(&frame[..frame.len() - 1]);
+        assert!(decoder.decode_frame(&mut received).unwrap().is_none());
+        received.extend_from_slice(&frame[frame.len() - 1..]);
+        assert!(decoder.decode_frame(&mut received).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_replayed_frame() {
+        let (mut encoder, mut decoder) = codec_pair(Capabilities::empty());
+        let frame = frame(&mut encoder, b"once");
+
+        decoder.decode_frame(&mut frame.clone()).unwrap().unwrap();
+        assert!(matches!(
+            decoder.decode_frame(&mut frame.clone()),
+            Err(NetworkError::CryptError(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_reordered_frames() {
+        let (mut encoder, mut decoder) = codec_pair(Capabilities::empty());
+        let _first = frame(&mut encoder, b"first");
+        let mut second = frame(&mut encoder, b"second");
+
+        assert!(matches!(
+            decoder.decode_frame(&mut second),
+            Err(NetworkError::CryptError(_))
+        ));
+    }
+
+    #[test]
+    fn authenticates_the_header() {
+        let (mut encoder, mut decoder) = codec_pair(Capabilities::ZSTD_COMPRESSION);
+        let mut frame = frame(&mut encoder, b"tampered");
+        frame[1] |= FLAG_COMPRESSED;
+
+        assert!(matches!(
+            decoder.decode_frame(&mut frame),
+            Err(NetworkError::CryptError(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_headers_before_the_frame() {
+        let (_, mut decoder) = codec_pair(Capabilities::empty());
+        let header = |version: u8, flags: u8, length: u32| {
+            let mut header = BytesMut::from(&[version, flags][..]);
+            header.extend_from_slice(&length.to_be_bytes());
+            header
+        };
+
+        let mut bad_version = header(FRAME_VERSION + 1, 0, 64);
+        let mut unknown_flags = header(FRAME_VERSION, 0x80, 64);
+        let mut shorter_than_tag = header(FRAME_VERSION, 0, TAG_SIZE as u32 - 1);
+        let mut too_large = header(FRAME_VERSION, 0, MAX + 1);
+
+        assert!(matches!(decoder.decode_frame(&mut bad_version), Err(NetworkError::InvalidFrame(_))));
+        assert!(matches!(decoder.decode_frame(&mut unknown_flags), Err(NetworkError::InvalidFrame(_))));
+        assert!(matches!(decoder.decode_frame(&mut shorter_than_tag), Err(NetworkError::InvalidFrame(_))));
+        assert!(matches!(
+            decoder.decode_frame(&mut too_large),
+            Err(NetworkError::FrameTooLarge { length, max: MAX }) if length == MAX as usize + 1
+        ));
+        // Only the header was received, nothing waits for the rest of the frame
+        assert!(too_large.capacity() < MAX as usize);
+    }
+
+    #[test]
+    fn refuses_to_encode_oversize_packet() {
+        let (mut encoder, mut decoder) = codec_pair(Capabilities::empty());
+        let packet = Packets::StreamData(StreamData {
+            stream_id: 1,
+            data: Bytes::from(vec![0; MAX as usize]),
+        });
+        let mut frames = BytesMut::new();
+
+        assert!(matches!(
+            encoder.encode_packet(&packet, &mut frames),
+            Err(NetworkError::FrameTooLarge { max: MAX, .. })
+        ));
+        assert!(frames.is_empty());
+
+        // Nothing was sealed, the next frame still has the expected sequence number
+        let close = Packets::StreamClose(StreamClose { stream_id: 1 });
+        encoder.encode_packet(&close, &mut frames).unwrap();
+        assert!(decoder.decode_frame(&mut frames).unwrap().is_some());
+    }
+
+    #[test]
+    fn compresses_large_stream_data() {
+        let (mut encoder, mut decoder) = codec_pair(Capabilities::LZ4_COMPRESSION);
+        let data = [b'a'; 2048];
+        let mut frame = frame(&mut encoder, &[b'a'; 2048]);
+
+        assert_ne!(frame[1] & FLAG_COMPRESSED, 0);
+        assert!(frame.len() < data.len());
+        let packet = decoder.decode_frame(&mut frame).unwrap().unwrap();
+        assert_eq!(stream_data(packet), &data[..]);
+    }
+
+    #[test]
+    fn key_updates_ratchet_both_directions() {
+        let (agent, server) = codecs(
+            Capabilities::KEY_UPDATE,
+            CipherSuite::Aes256Gcm,
+            RekeyPolicy::default(),
+        );
+        let (mut agent_encoder, mut agent_decoder) = agent.into_split();
+        let (mut server_encoder, mut server_decoder) = server.into_split();
+
+        // The agent asks for an update, every frame after it is sealed with the next generation
+        let mut to_server = BytesMut::new();
+        let request = Packets::KeyUpdate(KeyUpdate::new(true));
+        agent_encoder.encode_packet(&request, &mut to_server).unwrap();
+        to_server.extend_from_slice(&frame(&mut agent_encoder, b"after"));
+        assert_eq!(agent_encoder.generation(), 1);
+
+        let update = server_decoder.decode_frame(&mut to_server).unwrap().unwrap();
+        assert!(key_update(update).update_requested);
+        assert_eq!(server_decoder.generation(), 1);
+        let data = server_decoder.decode_frame(&mut to_server).unwrap().unwrap();
+        assert_eq!(stream_data(data), &b"after"[..]);
+
+        // The server answers and updates its own direction
+        let mut to_agent = frame(&mut server_encoder, b"before");
+        let answer = Packets::KeyUpdate(KeyUpdate::new(false));
+        server_encoder.encode_packet(&answer, &mut to_agent).unwrap();
+        to_agent.extend_from_slice(&frame(&mut server_encoder, b"after"));
+        assert_eq!(server_encoder.generation(), 1);
+
+        let data = agent_decoder.decode_frame(&mut to_agent).unwrap().unwrap();
+        assert_eq!(stream_data(data), &b"before"[..]);
+        assert_eq!(agent_decoder.generation(), 0);
+        let update = agent_decoder.decode_frame(&mut to_agent).unwrap().unwrap();
+        assert!(!key_update(update).update_requested);
+        let data = agent_decoder.decode_frame(&mut to_agent).unwrap().unwrap();
+        assert_eq!(stream_data(data), &b"after"[..]);
+        assert_eq!(agent_decoder.generation(), 1);
+    }
+
+    #[test]
+    fn frames_of_the_next_generation_need_the_key_update() {
+        let (mut encoder, mut decoder) = codec_pair(Capabilities::KEY_UPDATE);
+        let mut update = BytesMut::new();
+        let request = Packets::KeyUpdate(KeyUpdate::new(true));
+        encoder.encode_packet(&request, &mut update).unwrap();
+        let mut next_generation = frame(&mut encoder, b"after");
+
+        // The sequence restarted with the new key, the old one can't open the frame
+        assert!(matches!(
+            decoder.decode_frame(&mut next_generation.clone()),
+            Err(NetworkError::CryptError(_))
+        ));
+        decoder.decode_frame(&mut update).unwrap().unwrap();
+        let data = decoder.decode_frame(&mut next_generation).unwrap().unwrap();
+        assert_eq!(stream_data(data), &b"after"[..]);
+    }
+
+    #[test]
+    fn sends_a_key_update_once_the_policy_is_exhausted() {
+        let policy = RekeyPolicy {
+            max_frames: 2,
+            ..RekeyPolicy::default()
+        };
+        let (agent, server) = codecs(Capabilities::KEY_UPDATE, CipherSuite::Aes256Gcm, policy);
+        let (mut encoder, _) = agent.into_split();
+        let (_, mut decoder) = server.into_split();
+
+        let mut received = frame(&mut encoder, b"first");
+        assert_eq!(encoder.generation(), 0);
+        received.extend_from_slice(&frame(&mut encoder, b"second"));
+        assert_eq!(encoder.generation(), 1);
+        received.extend_from_slice(&frame(&mut encoder, b"third"));
+
+        let first = decoder.decode_frame(&mut received).unwrap().unwrap();
+        let second = decoder.decode_frame(&mut received).unwrap().unwrap();
+        let update = decoder.decode_frame(&mut received).unwrap().unwrap();
+        let third = decoder.decode_frame(&mut received).unwrap().unwrap();
+        assert_eq!(stream_data(first), &b"first"[..]);
+        assert_eq!(stream_data(second), &b"second"[..]);
+        assert!(key_update(update).update_requested);
+        assert_eq!(stream_data(third), &b"third"[..]);
+        assert_eq!(decoder.generation(), 1);
+    }
+
+    #[test]
+    fn exhausted_policy_without_key_updates_keeps_the_key() {
+        let policy = RekeyPolicy {
+            max_frames: 1,
+            ..RekeyPolicy::default()
+        };
+        let (agent, server) = codecs(Capabilities::empty(), CipherSuite::Aes256Gcm, policy);
+        let (mut encoder, _) = agent.into_split();
+        let (_, mut decoder) = server.into_split();
+
+        let mut received = frame(&mut encoder, b"first");
+        received.extend_from_slice(&frame(&mut encoder, b"second"));
+
+        assert_eq!(encoder.generation(), 0);
+        let first = decoder.decode_frame(&mut received).unwrap().unwrap();
+        let second = decoder.decode_frame(&mut received).unwrap().unwrap();
+        assert_eq!(stream_data(first), &b"first"[..]);
+        assert_eq!(stream_data(second), &b"second"[..]);
+    }
+
+    #[test]
+    fn frames_round_trip_with_every_suite() {
+        for suite in CipherSuite::ALL {
+            let (agent, server) = codecs(Capabilities::empty(), suite, RekeyPolicy::default());
+            let (mut encoder, _) = agent.into_split();
+            let (_, mut decoder) = server.into_split();
+
+            let mut received = frame(&mut encoder, b"suite");
+            let packet = decoder.decode_frame(&mut received).unwrap().unwrap();
+            assert_eq!(stream_data(packet), &b"suite"[..]);
+        }
+    }
+}
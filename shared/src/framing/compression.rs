@@ -0,0 +1,169 @@
+use std::fmt;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::error::NetworkError;
+use crate::handshake::Capabilities;
+
+/// Stream Data packets serialized to fewer bytes are sent raw, compressing them rarely pays off
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+/// Fast while still shrinking JSON and logs well
+const ZSTD_LEVEL: i32 = 3;
+
+/// Size in bytes of the little-endian uncompressed length prefixing LZ4 blocks
+const LZ4_LENGTH_SIZE: usize = 4;
+
+/// Algorithm compressing large Stream Data packets of a connection, picked from the negotiated
+/// capabilities. Both peers pick the same one, the frame flag only tells whether it was applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+/// Compression context of the sending half, reused across frames
+pub(super) enum Compressor {
+    Zstd(Box<zstd::bulk::Compressor<'static>>),
+    Lz4,
+}
+
+/// Decompression context of the receiving half, reused across frames
+pub(super) enum Decompressor {
+    Zstd(Box<zstd::bulk::Decompressor<'static>>),
+    Lz4,
+}
+
+impl Compression {
+    /// Algorithm used on a connection, zstd when both are negotiated
+    pub fn negotiate(capabilities: Capabilities) -> Option<Compression> {
+        if capabilities.contains(Capabilities::ZSTD_COMPRESSION) {
+            Some(Compression::Zstd)
+        } else if capabilities.contains(Capabilities::LZ4_COMPRESSION) {
+            Some(Compression::Lz4)
+        } else {
+            None
+        }
+    }
+}
+
+impl Compressor {
+    pub(super) fn new(compression: Compression) -> Self {
+        match compression {
+            Compression::Zstd => Compressor::Zstd(Box::new(
+                zstd::bulk::Compressor::new(ZSTD_LEVEL).expect("valid zstd compression level"),
+            )),
+            Compression::Lz4 => Compressor::Lz4,
+        }
+    }
+
+    /// Append the compressed `packet` to `dst`, its uncompressed length included
+    pub(super) fn compress_into(
+        &mut self,
+        packet: &[u8],
+        dst: &mut BytesMut,
+    ) -> Result<(), NetworkError> {
+        let start = dst.len();
+        match self {
+            Compressor::Zstd(compressor) => {
+                // zstd frames record their content size
+                dst.resize(start + zstd::zstd_safe::compress_bound(packet.len()), 0);
+                let length = compressor
+                    .compress_to_buffer(packet, &mut dst[start..])
+                    .map_err(|e| NetworkError::CompressionError(e.to_string()))?;
+                dst.truncate(start + length);
+            }
+            Compressor::Lz4 => {
+                dst.put_u32_le(packet.len() as u32);
+                let block_start = start + LZ4_LENGTH_SIZE;
+                dst.resize(
+                    block_start + lz4_flex::block::get_maximum_output_size(packet.len()),
+                    0,
+                );
+                let length = lz4_flex::block::compress_into(packet, &mut dst[block_start..])
+                    .map_err(|e| NetworkError::CompressionError(e.to_string()))?;
+                dst.truncate(block_start + length);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decompressor {
+    pub(super) fn new(compression: Compression) -> Self {
+        match compression {
+            Compression::Zstd => Decompressor::Zstd(Box::new(
+                zstd::bulk::Decompressor::new().expect("zstd decompression context"),
+            )),
+            Compression::Lz4 => Decompressor::Lz4,
+        }
+    }
+
+    /// Decompress a packet compressed by the peer. Its uncompressed length is checked against
+    /// `max_size` before anything is allocated.
+    pub(super) fn decompress(
+        &mut self,
+        compressed: &[u8],
+        max_size: usize,
+    ) -> Result<BytesMut, NetworkError> {
+        let (size, body) = match self {
+            Decompressor::Zstd(_) => match zstd::zstd_safe::get_frame_content_size(compressed) {
+                Ok(Some(size)) => (usize::try_from(size).unwrap_or(usize::MAX), compressed),
+                _ => {
+                    return Err(NetworkError::InvalidFrame(
+                        "compressed packet without a valid content size".to_string(),
+                    ));
+                }
+            },
+            Decompressor::Lz4 => {
+                let Some((length, body)) = compressed.split_first_chunk::<LZ4_LENGTH_SIZE>() else {
+                    return Err(NetworkError::InvalidFrame(
+                        "compressed packet without its length".to_string(),
+                    ));
+                };
+                (u32::from_le_bytes(*length) as usize, body)
+            }
+        };
+        if size > max_size {
+            return Err(NetworkError::InvalidFrame(format!(
+                "compressed packet of {size} bytes exceeds the maximum of {max_size} bytes"
+            )));
+        }
+
+        let mut packet = BytesMut::zeroed(size);
+        let length = match self {
+            Decompressor::Zstd(decompressor) => decompressor
+                .decompress_to_buffer(body, &mut packet[..])
+                .map_err(|e| e.to_string()),
+            Decompressor::Lz4 => {
+                lz4_flex::block::decompress_into(body, &mut packet).map_err(|e| e.to_string())
+            }
+        }
+        .map_err(|e| NetworkError::InvalidFrame(format!("failed to decompress packet: {e}")))?;
+
+        if length != size {
+            return Err(NetworkError::InvalidFrame(format!(
+                "compressed packet of {size} bytes decompressed to {length} bytes"
+            )));
+        }
+        Ok(packet)
+    }
+}
+
+impl fmt::Debug for Compressor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compressor::Zstd(_) => f.write_str("Compressor(Zstd)"),
+            Compressor::Lz4 => f.write_str("Compressor(Lz4)"),
+        }
+    }
+}
+
+impl fmt::Debug for Decompressor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decompressor::Zstd(_) => f.write_str("Decompressor(Zstd)"),
+            Decompressor::Lz4 => f.write_str("Decompressor(Lz4)"),
+        }
+    }
+}
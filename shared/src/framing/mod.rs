@@ -0,0 +1,101 @@
+mod codec;
+mod compression;
+
+use crate::encryption::TAG_SIZE;
+use crate::error::NetworkError;
+
+pub use codec::{FrameCodec, FrameDecoder, FrameEncoder};
+pub use compression::{COMPRESSION_THRESHOLD, Compression};
+
+/// Version of the frame format, bumped on any incompatible change
+pub const FRAME_VERSION: u8 = 1;
+
+/// Size in bytes of an encoded `FrameHeader`
+pub const HEADER_SIZE: usize = 6;
+
+/// Flag of frames whose packet was compressed with the negotiated `Compression` before encryption
+pub const FLAG_COMPRESSED: u8 = 1 << 0;
+
+/// Flags understood by this version, frames with any other bit set are rejected
+pub const KNOWN_FLAGS: u8 = FLAG_COMPRESSED;
+
+/// Maximum frame size used when none is configured, in bytes of ciphertext
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 1024 * 1024;
+
+/// Smallest maximum frame size a peer can ask for, every control packet fits in it
+pub const MIN_FRAME_SIZE: u32 = 4 * 1024;
+
+/// Largest maximum frame size a peer can ask for
+pub const MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Header sent in clear before every encrypted frame.
+///
+/// It is authenticated as associated data of the frame ciphertext, tampering with it
+/// makes the frame fail to decrypt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub flags: u8,
+    /// Length of the ciphertext following the header, tag included
+    pub length: u32,
+}
+
+impl FrameHeader {
+    pub fn new(flags: u8, length: u32) -> Self {
+        FrameHeader {
+            version: FRAME_VERSION,
+            flags,
+            length,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0] = self.version;
+        bytes[1] = self.flags;
+        bytes[2..].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    /// Parse and validate a header received from the peer.
+    /// Frames longer than `max_frame_size` are rejected before their ciphertext is read.
+    pub fn from_bytes(bytes: &[u8; HEADER_SIZE], max_frame_size: u32) -> Result<Self, NetworkError> {
+        let header = FrameHeader {
+            version: bytes[0],
+            flags: bytes[1],
+            length: u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+        };
+
+        if header.version != FRAME_VERSION {
+            return Err(NetworkError::InvalidFrame(format!(
+                "unsupported version {}",
+                header.version
+            )));
+        }
+        if header.flags & !KNOWN_FLAGS != 0 {
+            return Err(NetworkError::InvalidFrame(format!(
+                "unknown flags {:#04x}",
+                header.flags
+            )));
+        }
+        if (header.length as usize) < TAG_SIZE {
+            return Err(NetworkError::InvalidFrame(format!(
+                "length {} is shorter than the authentication tag",
+                header.length
+            )));
+        }
+        if header.length > max_frame_size {
+            return Err(NetworkError::FrameTooLarge {
+                length: header.length as usize,
+                max: max_frame_size,
+            });
+        }
+
+        Ok(header)
+    }
+}
+
+/// Maximum frame size of a connection, the smallest of both peers within the protocol bounds
+pub fn negotiate_frame_size(offered: u32, local: u32) -> u32 {
+    offered.min(local).clamp(MIN_FRAME_SIZE, MAX_FRAME_SIZE)
+}
@@ -0,0 +1,374 @@
+use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::codes::wire_code;
+use crate::encryption::SecretKey;
+use crate::error::NetworkError;
+use crate::identity::{IdentityError, decode_secret_key};
+use crate::packets::{EncryptionRequest, EncryptionResponse};
+
+/// Domain separation label mixed into every handshake transcript
+const TRANSCRIPT_LABEL: &[u8] = b"tcp-server-boilerplate handshake v1";
+
+/// Newest protocol version spoken by this build
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest protocol version this build can still talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Size in bytes of the big-endian length prefixing every handshake packet
+pub const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Largest handshake packet accepted, checked before allocating its buffer
+pub const MAX_HANDSHAKE_PACKET_SIZE: usize = 16 * 1024;
+
+/// Why the server refused a handshake, sent in a Handshake Reject packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectCode {
+    /// No protocol version in common, see `NetworkError::UnsupportedProtocolVersion`
+    UnsupportedVersion,
+    /// The agent identity isn't in the server trust store
+    AuthenticationFailed,
+    /// The agent identity or address is banned
+    Banned,
+    /// The server is at its connection limit
+    Overloaded,
+    /// The agent doesn't offer a capability the server requires
+    MissingCapabilities,
+    /// Sent by a newer server, unknown to this build
+    Unknown(u8),
+}
+
+wire_code!(RejectCode {
+    UnsupportedVersion = 0,
+    AuthenticationFailed = 1,
+    Banned = 2,
+    Overloaded = 3,
+    MissingCapabilities = 4,
+});
+
+/// Optional protocol features, advertised by the agent and confirmed by the server.
+/// A feature is only used on a connection when both peers support it.
+#[derive(Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Capabilities(u32);
+
+/// How the peers authenticate each other, proposed by the agent and confirmed by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum HandshakeMode {
+    /// Server identity pinned by the agent, agent identity checked against the server trust store
+    Identity,
+    /// Both peers prove they know the same pre-shared key, no trust store or pin needed
+    PreSharedKey,
+}
+
+/// Secret shared out of band between the server and its agents
+pub struct PreSharedKey(SecretKey);
+
+/// Running hash of every handshake message, signed by the server so the
+/// agent can detect tampering with the exchanged keys
+#[derive(Clone)]
+pub struct Transcript {
+    hasher: Sha256,
+}
+
+impl RejectCode {
+    /// Whether connecting again later may succeed without changing the agent
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, RejectCode::Overloaded)
+    }
+}
+
+impl Capabilities {
+    /// Peers rotate their sending keys with `KeyUpdate` packets
+    pub const KEY_UPDATE: Capabilities = Capabilities(1 << 0);
+
+    /// Hybrid X25519 + ML-KEM-768 key exchange, protecting recorded traffic against quantum computers
+    pub const HYBRID_KEM: Capabilities = Capabilities(1 << 1);
+
+    /// Large Stream Data packets are compressed with zstd, preferred over LZ4 when both are negotiated
+    pub const ZSTD_COMPRESSION: Capabilities = Capabilities(1 << 2);
+
+    /// Large Stream Data packets are compressed with LZ4, faster than zstd but compressing less
+    pub const LZ4_COMPRESSION: Capabilities = Capabilities(1 << 3);
+
+    /// Stream Data is bounded by receive windows the peers grow with `WindowUpdate` packets
+    pub const FLOW_CONTROL: Capabilities = Capabilities(1 << 4);
+
+    /// Every capability implemented by this build
+    pub const SUPPORTED: Capabilities = Capabilities::KEY_UPDATE
+        .union(Capabilities::HYBRID_KEM)
+        .union(Capabilities::ZSTD_COMPRESSION)
+        .union(Capabilities::LZ4_COMPRESSION)
+        .union(Capabilities::FLOW_CONTROL);
+
+    /// Capabilities enabled unless configured otherwise. Compression is opt-in, it leaks how
+    /// compressible payloads are through the frame lengths.
+    pub const DEFAULT: Capabilities = Capabilities::SUPPORTED
+        .difference(Capabilities::ZSTD_COMPRESSION)
+        .difference(Capabilities::LZ4_COMPRESSION);
+
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn union(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub const fn difference(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+
+    pub const fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Capabilities enabled for a connection, unknown bits of a newer peer are dropped
+    pub const fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0 & Self::SUPPORTED.0)
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Capabilities::KEY_UPDATE, "KEY_UPDATE"),
+            (Capabilities::HYBRID_KEM, "HYBRID_KEM"),
+            (Capabilities::ZSTD_COMPRESSION, "ZSTD_COMPRESSION"),
+            (Capabilities::LZ4_COMPRESSION, "LZ4_COMPRESSION"),
+            (Capabilities::FLOW_CONTROL, "FLOW_CONTROL"),
+        ];
+        let mut known = names
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| name.to_string())
+            .collect::<Vec<_>>();
+        let unknown = self.difference(Capabilities::SUPPORTED).0;
+        if unknown != 0 {
+            known.push(format!("{unknown:#x}"));
+        }
+        write!(f, "Capabilities({})", known.join(" | "))
+    }
+}
+
+impl PreSharedKey {
+    pub fn new(secret: SecretKey) -> Self {
+        PreSharedKey(secret)
+    }
+
+    /// Load a hex encoded 32 bytes key from `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, IdentityError> {
+        let content = Zeroizing::new(fs::read_to_string(path)?);
+        Ok(PreSharedKey(decode_secret_key(&content)?))
+    }
+
+    pub fn secret(&self) -> &SecretKey {
+        &self.0
+    }
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(TRANSCRIPT_LABEL);
+        Transcript { hasher }
+    }
+
+    /// Transcript the server signs in its Encryption Response: the serialized Encryption Request,
+    /// then every field of the response but the verify token, its nonce and the signature. Both
+    /// peers build it here so a field negotiated in the response is always signed by one and
+    /// checked by the other.
+    pub fn server_signed(request: &[u8], response: &EncryptionResponse) -> Self {
+        let mut transcript = Transcript::new();
+        transcript.update(request);
+        transcript.update(&response.key);
+        transcript.update(response.kem_ciphertext.as_deref().unwrap_or_default());
+        transcript.update(&response.identity_key);
+        transcript.update(&[response.mode as u8]);
+        transcript.update(&[response.cipher_suite as u8]);
+        transcript.update(&response.version.to_be_bytes());
+        transcript.update(&response.capabilities.bits().to_be_bytes());
+        transcript.update(&[response.resumed as u8]);
+        transcript.update(&response.max_frame_size.to_be_bytes());
+        transcript
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        // Length prefix every entry so two different message splits never hash the same
+        self.hasher.update((data.len() as u32).to_be_bytes());
+        self.hasher.update(data);
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+impl Default for Transcript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Highest protocol version supported by both peers
+pub fn negotiate_version(agent: &RangeInclusive<u16>, server: &RangeInclusive<u16>) -> Option<u16> {
+    let version = *agent.end().min(server.end());
+    (version >= *agent.start().max(server.start())).then_some(version)
+}
+
+/// Versions supported by the agent, read from a serialized Encryption Request without decoding the rest.
+/// The version range always comes first so a server can reject an agent whose request it can't decode.
+pub fn peek_version_range(packet: &[u8]) -> Option<RangeInclusive<u16>> {
+    use crate::packets::Packet;
+
+    let (code, data) = packet.split_first()?;
+    if *code != EncryptionRequest::packet_code() {
+        return None;
+    }
+
+    let ((min, max), _): ((u16, u16), _) =
+        bincode::decode_from_slice(data, bincode::config::standard()).ok()?;
+    Some(min..=max)
+}
+
+/// Prefix a serialized handshake packet with its length
+pub fn encode_handshake_packet(packet: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(LENGTH_PREFIX_SIZE + packet.len());
+    data.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+    data.extend_from_slice(packet);
+    data
+}
+
+/// Length of the handshake packet following `prefix`
+pub fn handshake_packet_length(prefix: [u8; LENGTH_PREFIX_SIZE]) -> Result<usize, NetworkError> {
+    let length = u32::from_be_bytes(prefix) as usize;
+    if length == 0 || length > MAX_HANDSHAKE_PACKET_SIZE {
+        return Err(NetworkError::InvalidFrame(format!(
+            "handshake packet of {length} bytes, expected 1 to {MAX_HANDSHAKE_PACKET_SIZE}"
+        )));
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::CipherSuite;
+    use crate::packets::{HandshakeReject, Packet};
+
+    #[test]
+    fn negotiates_highest_common_version() {
+        assert_eq!(negotiate_version(&(1..=3), &(2..=5)), Some(3));
+        assert_eq!(negotiate_version(&(1..=1), &(1..=1)), Some(1));
+        assert_eq!(negotiate_version(&(1..=2), &(3..=4)), None);
+        assert_eq!(negotiate_version(&(5..=6), &(1..=4)), None);
+    }
+
+    #[test]
+    fn peeks_version_range_of_request() {
+        let request = EncryptionRequest::new(
+            Capabilities::DEFAULT,
+            [0; 32],
+            0,
+            [0; 32],
+            HandshakeMode::Identity,
+            CipherSuite::to_mask(&CipherSuite::ALL),
+            None,
+            None,
+            4096,
+        );
+        let packet = request.serialize().unwrap();
+
+        assert_eq!(
+            peek_version_range(&packet),
+            Some(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION)
+        );
+        assert_eq!(peek_version_range(&packet[..2]), None);
+        assert_eq!(peek_version_range(&[0x02, 1, 1]), None);
+    }
+
+    #[test]
+    fn server_signed_transcript_covers_negotiated_fields() {
+        let response = || {
+            EncryptionResponse::new(
+                PROTOCOL_VERSION,
+                Capabilities::DEFAULT,
+                [1; 32],
+                [0; 12],
+                [0; 24],
+                [2; 32],
+                [0; 64],
+                HandshakeMode::Identity,
+                CipherSuite::Aes256Gcm,
+                None,
+                false,
+                4096,
+            )
+        };
+        let hash = |response: &EncryptionResponse| Transcript::server_signed(b"request", response).hash();
+        let signed = hash(&response());
+
+        // Filled in after hashing
+        let mut unsigned = response();
+        unsigned.nonce = [3; 12];
+        unsigned.verify_token = [3; 24];
+        unsigned.signature = [3; 64];
+        assert_eq!(hash(&unsigned), signed);
+
+        let changes: [fn(&mut EncryptionResponse); 9] = [
+            |response| response.version += 1,
+            |response| response.capabilities = Capabilities::empty(),
+            |response| response.key = [9; 32],
+            |response| response.kem_ciphertext = Some(vec![9]),
+            |response| response.identity_key = [9; 32],
+            |response| response.mode = HandshakeMode::PreSharedKey,
+            |response| response.cipher_suite = CipherSuite::ChaCha20Poly1305,
+            |response| response.resumed = true,
+            |response| response.max_frame_size += 1,
+        ];
+        for change in changes {
+            let mut changed = response();
+            change(&mut changed);
+            assert_ne!(hash(&changed), signed);
+        }
+        assert_ne!(Transcript::server_signed(b"other request", &response()).hash(), signed);
+    }
+
+    #[test]
+    fn bounds_handshake_packet_length() {
+        assert!(handshake_packet_length(0u32.to_be_bytes()).is_err());
+        assert_eq!(handshake_packet_length(1u32.to_be_bytes()).unwrap(), 1);
+        let max = MAX_HANDSHAKE_PACKET_SIZE as u32;
+        assert!(handshake_packet_length(max.to_be_bytes()).is_ok());
+        assert!(handshake_packet_length((max + 1).to_be_bytes()).is_err());
+    }
+
+    #[test]
+    fn reject_codes_round_trip_as_a_byte() {
+        for code in [RejectCode::Banned, RejectCode::MissingCapabilities, RejectCode::Unknown(200)] {
+            let packet = HandshakeReject::new(code, "").serialize().unwrap();
+            // Packet code, reject code, empty message
+            assert_eq!(packet.len(), 3);
+            assert_eq!(HandshakeReject::deserialize(&packet[1..]).unwrap().code, code);
+        }
+        assert!(RejectCode::Overloaded.is_retryable());
+        assert!(!RejectCode::Unknown(3).is_retryable());
+    }
+
+    #[test]
+    fn capabilities_default_leaves_compression_out() {
+        assert!(Capabilities::SUPPORTED.contains(Capabilities::DEFAULT));
+        assert!(!Capabilities::DEFAULT.contains(Capabilities::ZSTD_COMPRESSION));
+        assert!(!Capabilities::DEFAULT.contains(Capabilities::LZ4_COMPRESSION));
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::encryption::SecretKey;
+
+/// Size in bytes of an encoded public identity key
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+/// Size in bytes of an identity signature
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Long-term Ed25519 keypair identifying a peer across connections
+pub struct IdentityKeypair {
+    signing_key: SigningKey,
+}
+
+/// Public half of an `IdentityKeypair`, safe to share and pin
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicIdentity([u8; PUBLIC_KEY_SIZE]);
+
+#[derive(Debug, Error)]
+pub enum IdentityError {
+    #[error("IO Error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Invalid key encoding: {0}")]
+    InvalidEncoding(String),
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+    #[error("Invalid signature")]
+    InvalidSignature,
+}
+
+impl IdentityKeypair {
+    pub fn generate() -> Self {
+        IdentityKeypair {
+            signing_key: SigningKey::from_bytes(SecretKey::random().expose_secret()),
+        }
+    }
+
+    /// Load a hex encoded secret key from `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, IdentityError> {
+        let content = Zeroizing::new(fs::read_to_string(path)?);
+        let secret = decode_secret_key(&content)?;
+        Ok(IdentityKeypair {
+            signing_key: SigningKey::from_bytes(secret.expose_secret()),
+        })
+    }
+
+    /// Store the secret key hex encoded in a new file at `path`, only readable by its owner on unix.
+    /// An existing file is never overwritten.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), IdentityError> {
+        let secret = SecretKey::from_bytes(self.signing_key.to_bytes());
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path)?;
+        file.write_all(Zeroizing::new(hex::encode(secret.expose_secret())).as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Load the keypair stored in `path`, generating and saving a new one if the file doesn't exist
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self, IdentityError> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Self::load(path);
+        }
+
+        let keypair = Self::generate();
+        keypair.save(path)?;
+        Ok(keypair)
+    }
+
+    pub fn public_key(&self) -> PublicIdentity {
+        PublicIdentity(self.signing_key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        self.signing_key.sign(message).to_bytes()
+    }
+}
+
+impl PublicIdentity {
+    pub fn from_bytes(bytes: [u8; PUBLIC_KEY_SIZE]) -> Self {
+        PublicIdentity(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.0
+    }
+
+    /// Parse a hex encoded public key, as written by `save`
+    pub fn from_hex(encoded: &str) -> Result<Self, IdentityError> {
+        Ok(PublicIdentity(decode_key(encoded)?))
+    }
+
+    /// Load a hex encoded public key from `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, IdentityError> {
+        Self::from_hex(&fs::read_to_string(path)?)
+    }
+
+    /// Store the public key hex encoded in `path`
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), IdentityError> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8; SIGNATURE_SIZE]) -> Result<(), IdentityError> {
+        let key = VerifyingKey::from_bytes(&self.0).map_err(|_| IdentityError::InvalidPublicKey)?;
+        key.verify(message, &Signature::from_bytes(signature))
+            .map_err(|_| IdentityError::InvalidSignature)
+    }
+}
+
+impl fmt::Display for PublicIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PublicIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicIdentity({})", self)
+    }
+}
+
+pub(crate) fn decode_key(encoded: &str) -> Result<[u8; 32], IdentityError> {
+    let bytes = hex::decode(encoded.trim()).map_err(|e| IdentityError::InvalidEncoding(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|_| IdentityError::InvalidEncoding("expected 32 bytes".to_string()))
+}
+
+/// Same as `decode_key`, wiping every intermediate copy of the secret
+pub(crate) fn decode_secret_key(encoded: &str) -> Result<SecretKey, IdentityError> {
+    let bytes = Zeroizing::new(
+        hex::decode(encoded.trim()).map_err(|e| IdentityError::InvalidEncoding(e.to_string()))?,
+    );
+    SecretKey::from_slice(&bytes)
+        .ok_or_else(|| IdentityError::InvalidEncoding("expected 32 bytes".to_string()))
+}
@@ -0,0 +1,205 @@
+use aes_gcm::aead::OsRng;
+use kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, EncodedSizeUser, KemCore, MlKem768};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::Zeroizing;
+
+use crate::encryption::SecretKey;
+use crate::error::NetworkError;
+
+/// ML-KEM parameter set of the hybrid key exchange
+type Kem = MlKem768;
+type DecapsulationKey = <Kem as KemCore>::DecapsulationKey;
+type EncapsulationKey = <Kem as KemCore>::EncapsulationKey;
+
+/// Ephemeral keys of the agent, an X25519 secret and in hybrid mode an ML-KEM decapsulation key
+pub struct AgentKeyShare {
+    dh_secret: EphemeralSecret,
+    kem_secret: Option<DecapsulationKey>,
+}
+
+/// Public part of the server key exchange, sent in the Encryption Response
+pub struct ServerKeyShare {
+    pub public_key: [u8; 32],
+    /// ML-KEM ciphertext, only in hybrid mode
+    pub kem_ciphertext: Option<Vec<u8>>,
+}
+
+/// Secrets agreed on by both peers, fed into the key schedule
+pub struct SharedSecret {
+    dh: SecretKey,
+    kem: Option<SecretKey>,
+}
+
+impl AgentKeyShare {
+    /// Generate fresh ephemeral keys, `hybrid` adds an ML-KEM key pair to the X25519 one
+    pub fn generate(hybrid: bool) -> Self {
+        AgentKeyShare {
+            dh_secret: EphemeralSecret::random_from_rng(OsRng),
+            kem_secret: hybrid.then(|| Kem::generate(&mut OsRng).0),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.dh_secret).to_bytes()
+    }
+
+    /// Encoded ML-KEM encapsulation key, `None` outside hybrid mode
+    pub fn kem_key(&self) -> Option<Vec<u8>> {
+        self.kem_secret
+            .as_ref()
+            .map(|secret| secret.encapsulation_key().as_bytes().to_vec())
+    }
+
+    /// Combine our keys with the server key share.
+    /// The server may leave out the ML-KEM ciphertext when it didn't enable the hybrid mode,
+    /// it can't send one we didn't ask for.
+    pub fn finish(
+        self,
+        server_key: [u8; 32],
+        kem_ciphertext: Option<&[u8]>,
+    ) -> Result<SharedSecret, NetworkError> {
+        let kem = match (self.kem_secret, kem_ciphertext) {
+            (Some(secret), Some(ciphertext)) => {
+                let ciphertext = Ciphertext::<Kem>::try_from(ciphertext).map_err(|_| {
+                    NetworkError::InvalidKeyShare(format!(
+                        "ML-KEM ciphertext of {} bytes",
+                        ciphertext.len()
+                    ))
+                })?;
+                let shared = secret.decapsulate(&ciphertext).map_err(|_| {
+                    NetworkError::InvalidKeyShare("ML-KEM decapsulation failed".to_string())
+                })?;
+                Some(SecretKey::from_slice(&shared).expect("ML-KEM shared keys are 32 bytes"))
+            }
+            (None, Some(_)) => {
+                return Err(NetworkError::InvalidKeyShare(
+                    "unrequested ML-KEM ciphertext".to_string(),
+                ));
+            }
+            (_, None) => None,
+        };
+
+        let dh = self.dh_secret.diffie_hellman(&PublicKey::from(server_key));
+        Ok(SharedSecret {
+            dh: SecretKey::from_slice(dh.as_bytes()).expect("X25519 shared secrets are 32 bytes"),
+            kem,
+        })
+    }
+}
+
+impl ServerKeyShare {
+    /// Answer the agent key share, encapsulating to its ML-KEM key when one is given
+    pub fn respond(
+        agent_key: [u8; 32],
+        kem_key: Option<&[u8]>,
+    ) -> Result<(ServerKeyShare, SharedSecret), NetworkError> {
+        let (kem_ciphertext, kem) = match kem_key {
+            Some(kem_key) => {
+                let encoded = kem_key.try_into().map_err(|_| {
+                    NetworkError::InvalidKeyShare(format!(
+                        "ML-KEM encapsulation key of {} bytes",
+                        kem_key.len()
+                    ))
+                })?;
+                let (ciphertext, shared) = EncapsulationKey::from_bytes(encoded)
+                    .encapsulate(&mut OsRng)
+                    .map_err(|_| {
+                        NetworkError::InvalidKeyShare("ML-KEM encapsulation failed".to_string())
+                    })?;
+                let shared =
+                    SecretKey::from_slice(&shared).expect("ML-KEM shared keys are 32 bytes");
+                (Some(ciphertext.to_vec()), Some(shared))
+            }
+            None => (None, None),
+        };
+
+        let dh_secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&dh_secret).to_bytes();
+        let dh = dh_secret.diffie_hellman(&PublicKey::from(agent_key));
+        let dh = SecretKey::from_slice(dh.as_bytes()).expect("X25519 shared secrets are 32 bytes");
+
+        Ok((
+            ServerKeyShare {
+                public_key,
+                kem_ciphertext,
+            },
+            SharedSecret { dh, kem },
+        ))
+    }
+}
+
+impl SharedSecret {
+    /// Input keying material of the key schedule, the ML-KEM secret followed by the X25519 one
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut ikm = Zeroizing::new(Vec::with_capacity(64));
+        if let Some(kem) = &self.kem {
+            ikm.extend_from_slice(kem.expose_secret());
+        }
+        ikm.extend_from_slice(self.dh.expose_secret());
+        ikm
+    }
+
+    /// Whether an ML-KEM secret was mixed in
+    pub fn is_hybrid(&self) -> bool {
+        self.kem.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hybrid_exchange_agrees() {
+        let agent = AgentKeyShare::generate(true);
+        let kem_key = agent.kem_key().unwrap();
+        let (server, server_secret) =
+            ServerKeyShare::respond(agent.public_key(), Some(&kem_key)).unwrap();
+
+        let agent_secret = agent
+            .finish(server.public_key, server.kem_ciphertext.as_deref())
+            .unwrap();
+
+        assert!(agent_secret.is_hybrid() && server_secret.is_hybrid());
+        assert_eq!(*agent_secret.to_bytes(), *server_secret.to_bytes());
+        assert_eq!(agent_secret.to_bytes().len(), 64);
+    }
+
+    #[test]
+    fn classic_exchange_agrees() {
+        let agent = AgentKeyShare::generate(false);
+        assert!(agent.kem_key().is_none());
+        let (server, server_secret) = ServerKeyShare::respond(agent.public_key(), None).unwrap();
+
+        let agent_secret = agent.finish(server.public_key, None).unwrap();
+
+        assert!(!agent_secret.is_hybrid());
+        assert_eq!(*agent_secret.to_bytes(), *server_secret.to_bytes());
+    }
+
+    #[test]
+    fn server_may_decline_hybrid() {
+        let agent = AgentKeyShare::generate(true);
+        let (server, server_secret) = ServerKeyShare::respond(agent.public_key(), None).unwrap();
+
+        let agent_secret = agent.finish(server.public_key, None).unwrap();
+
+        assert!(!agent_secret.is_hybrid());
+        assert_eq!(*agent_secret.to_bytes(), *server_secret.to_bytes());
+    }
+
+    #[test]
+    fn rejects_unrequested_or_malformed_ciphertext() {
+        let agent = AgentKeyShare::generate(false);
+        let unrequested = agent.finish([9; 32], Some(&[0; 1088]));
+        assert!(matches!(unrequested, Err(NetworkError::InvalidKeyShare(_))));
+
+        let agent = AgentKeyShare::generate(true);
+        let truncated = agent.finish([9; 32], Some(&[0; 16]));
+        assert!(matches!(truncated, Err(NetworkError::InvalidKeyShare(_))));
+
+        let invalid_key = ServerKeyShare::respond([9; 32], Some(&[0; 16]));
+        assert!(matches!(invalid_key, Err(NetworkError::InvalidKeyShare(_))));
+    }
+}
@@ -1,6 +1,11 @@
+mod codes;
 pub mod encryption;
 pub mod packets;
 pub mod multiplexing;
 pub mod error;
+pub mod framing;
+pub mod handshake;
+pub mod identity;
+pub mod key_exchange;
 
-pub use derive::Packet;
\ No newline at end of file
+pub use derive::Packet;
@@ -0,0 +1,78 @@
+use crate::error::NetworkError;
+
+use super::StreamId;
+
+/// Bytes of Stream Data a peer may send on a stream before it is granted more
+pub const INITIAL_STREAM_WINDOW: u32 = 256 * 1024;
+
+/// Bytes of Stream Data a peer may send on all its streams together before it is granted more
+pub const INITIAL_CONNECTION_WINDOW: u32 = 1024 * 1024;
+
+/// Credit the peer granted us to send Stream Data
+#[derive(Debug)]
+pub(super) struct SendWindow(u32);
+
+/// Credit we granted the peer, given back as the application reads what it sent
+#[derive(Debug)]
+pub(super) struct ReceiveWindow {
+    size: u32,
+    available: u32,
+    /// Read by the application but not announced to the peer yet
+    released: u32,
+}
+
+impl SendWindow {
+    pub(super) fn new(size: u32) -> Self {
+        SendWindow(size)
+    }
+
+    pub(super) fn available(&self) -> usize {
+        self.0 as usize
+    }
+
+    /// Use credit for `length` bytes, at most `available`
+    pub(super) fn consume(&mut self, length: usize) {
+        self.0 -= length as u32;
+    }
+
+    /// Add the credit of a Window Update, a window past `u32::MAX` is a protocol violation
+    pub(super) fn grant(&mut self, stream_id: StreamId, increment: u32) -> Result<(), NetworkError> {
+        self.0 = self
+            .0
+            .checked_add(increment)
+            .ok_or(NetworkError::FlowControlViolation(stream_id))?;
+        Ok(())
+    }
+}
+
+impl ReceiveWindow {
+    pub(super) fn new(size: u32) -> Self {
+        ReceiveWindow {
+            size,
+            available: size,
+            released: 0,
+        }
+    }
+
+    /// Account for `length` bytes received, more than the peer was granted is a protocol violation
+    pub(super) fn receive(&mut self, stream_id: StreamId, length: usize) -> Result<(), NetworkError> {
+        self.available = u32::try_from(length)
+            .ok()
+            .and_then(|length| self.available.checked_sub(length))
+            .ok_or(NetworkError::FlowControlViolation(stream_id))?;
+        Ok(())
+    }
+
+    /// Give back credit for `length` bytes read by the application. The credit is returned to
+    /// announce in a Window Update once half the window was read, to avoid a flood of small ones.
+    pub(super) fn release(&mut self, length: usize) -> Option<u32> {
+        self.released += length as u32;
+        if self.released < self.size / 2 {
+            return None;
+        }
+
+        let increment = std::mem::take(&mut self.released);
+        self.available += increment;
+        Some(increment)
+    }
+}
@@ -1,8 +1,69 @@
+mod flow_control;
+mod multiplexer;
+
+pub use flow_control::{INITIAL_CONNECTION_WINDOW, INITIAL_STREAM_WINDOW};
+pub use multiplexer::{Event, Multiplexer, READ_BUFFER_SIZE};
+
+use crate::codes::wire_code;
+
 /// Stream ID type alias for clarity
 pub type StreamId = u32;
 
-/// Control stream ID (reserved for protocol control messages)
+/// Control stream ID (reserved for protocol control messages such as `KeyUpdate`)
 pub const CONTROL_STREAM_ID: StreamId = 0;
 
 /// Minimum stream ID for application data
 pub const MIN_DATA_STREAM_ID: StreamId = 1;
+
+/// Why a stream was reset, sent in a Stream Reset packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCode {
+    /// The application gave up on the stream
+    Cancelled,
+    /// The receiver doesn't accept the stream
+    Refused,
+    /// The application failed while handling the stream
+    InternalError,
+    /// The peer broke the protocol on the stream
+    ProtocolError,
+    /// The peer sent more than its window on the stream allowed
+    FlowControlError,
+    /// The peer sent on the stream after finishing it
+    StreamClosed,
+    /// Sent by a newer peer, unknown to this build
+    Unknown(u8),
+}
+
+wire_code!(ResetCode {
+    Cancelled = 0,
+    Refused = 1,
+    InternalError = 2,
+    ProtocolError = 3,
+    FlowControlError = 4,
+    StreamClosed = 5,
+});
+
+/// Side of the connection a multiplexer runs on. Each side allocates the IDs of the streams it
+/// opens from its own half of the ID space, so both can open streams at once without colliding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Opens odd stream IDs, from 1
+    Agent,
+    /// Opens even stream IDs, from 2
+    Server,
+}
+
+impl Role {
+    /// First stream ID allocated by this side
+    pub const fn first_stream_id(&self) -> StreamId {
+        match self {
+            Role::Agent => MIN_DATA_STREAM_ID,
+            Role::Server => MIN_DATA_STREAM_ID + 1,
+        }
+    }
+
+    /// Whether `stream_id` belongs to the IDs this side allocates
+    pub const fn allocates(&self, stream_id: StreamId) -> bool {
+        stream_id != CONTROL_STREAM_ID && stream_id % 2 == self.first_stream_id() % 2
+    }
+}
@@ -0,0 +1,889 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::encryption::TAG_SIZE;
+use crate::error::NetworkError;
+use crate::handshake::Capabilities;
+use crate::packets::{
+    Heartbeat, KeyUpdate, Packets, STREAM_DATA_OVERHEAD, StreamClose, StreamData, StreamOpen, StreamReset,
+    WindowUpdate, from_packet_buf, packet_stream_id,
+};
+
+use super::flow_control::{
+    INITIAL_CONNECTION_WINDOW, INITIAL_STREAM_WINDOW, ReceiveWindow, SendWindow,
+};
+use super::{CONTROL_STREAM_ID, ResetCode, Role, StreamId};
+
+/// Bytes drivers read from the socket at once before handing them to the frame decoder
+pub const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Protocol state of an established connection, without any IO.
+///
+/// Drivers feed it the packets decoded by a `FrameDecoder` with `receive`, then handle the
+/// `Event`s it produced with `poll_event` and encode the packets it queued with `poll_transmit`,
+/// in order, with a `FrameEncoder`. The codec switches keys on the Key Updates it carries.
+///
+/// Streams close in each direction on its own: `finish` ends what we send, a Stream Close from
+/// the peer ends what it sends. The state of a stream is dropped once both directions ended, or
+/// right away when either peer resets it.
+///
+/// With `FLOW_CONTROL`, drivers `release` the Stream Data the application read so the peer is
+/// granted more, and send again what `send` left over once an `Event::WindowUpdated` arrives.
+#[derive(Debug)]
+pub struct Multiplexer {
+    role: Role,
+    capabilities: Capabilities,
+    /// Largest frame the peer accepts, packets that don't fit are refused before being queued
+    max_frame_size: u32,
+    streams: HashMap<StreamId, StreamState>,
+    /// Next ID of our half of the ID space, `None` once it is exhausted
+    next_id: Option<StreamId>,
+    /// Highest ID the peer opened a stream with, it opens the next ones above so IDs of streams
+    /// it closed are never reused
+    peer_last_id: StreamId,
+    /// Windows shared by every stream, only enforced with `FLOW_CONTROL`
+    send_window: SendWindow,
+    receive_window: ReceiveWindow,
+    /// Key updates received, the generation of the peer sending key
+    peer_generation: u64,
+    transmit: VecDeque<Packets>,
+    events: VecDeque<Event>,
+}
+
+/// Direction and flow control state of an open stream
+#[derive(Debug)]
+struct StreamState {
+    /// We didn't finish sending yet
+    sending: bool,
+    /// The peer didn't finish sending yet
+    receiving: bool,
+    send_window: SendWindow,
+    receive_window: ReceiveWindow,
+    /// Received and not released yet, given back to the connection when the stream closes
+    unreleased: usize,
+}
+
+/// Something that happened on the connection, decoded by `Multiplexer::receive`
+#[derive(Debug)]
+pub enum Event {
+    /// The peer opened a stream
+    StreamOpened(StreamId),
+    StreamData { stream_id: StreamId, data: Bytes },
+    /// The peer finished sending on a stream, no more data follows
+    StreamFinished(StreamId),
+    StreamError { stream_id: StreamId, error: String },
+    /// The peer reset a stream, both directions ended and data in flight is dropped
+    StreamReset { stream_id: StreamId, code: ResetCode },
+    /// The peer broke the protocol on a stream, we reset it with `code`
+    StreamFailed { stream_id: StreamId, code: ResetCode, error: NetworkError },
+    /// The server issued a ticket to resume the session on the next connection
+    SessionTicket { lifetime: Duration, ticket: Vec<u8> },
+    /// The peer rotated its sending key to a new generation
+    PeerKeyUpdated { generation: u64 },
+    /// The peer granted more credit to a stream, or to every stream for the control stream
+    WindowUpdated(StreamId),
+    /// Data received for a stream that isn't open, it was dropped
+    UnknownStream(StreamId),
+    /// A packet that has no meaning on an established connection, it was dropped
+    UnexpectedPacket,
+}
+
+impl Multiplexer {
+    pub fn new(role: Role, capabilities: Capabilities, max_frame_size: u32) -> Self {
+        Multiplexer {
+            role,
+            capabilities,
+            max_frame_size,
+            streams: HashMap::new(),
+            next_id: Some(role.first_stream_id()),
+            peer_last_id: CONTROL_STREAM_ID,
+            send_window: SendWindow::new(INITIAL_CONNECTION_WINDOW),
+            receive_window: ReceiveWindow::new(INITIAL_CONNECTION_WINDOW),
+            peer_generation: 0,
+            transmit: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Handle a packet received from the peer. An error confined to the stream of the packet
+    /// resets that stream and produces an `Event::StreamFailed`, the connection goes on.
+    /// Any other error is fatal, the connection must be closed.
+    pub fn receive(&mut self, packet: Bytes) -> Result<(), NetworkError> {
+        // Read first, a packet that fails to decode may still be traced back to its stream
+        let stream_id = packet_stream_id(&packet).filter(|id| *id != CONTROL_STREAM_ID);
+        let Err(error) = self.handle_packet(packet) else {
+            return Ok(());
+        };
+        let (Some(stream_id), Some(code)) = (stream_id, error.reset_code()) else {
+            return Err(error);
+        };
+
+        self.remove_stream(stream_id);
+        self.queue_packet(StreamReset { stream_id, code });
+        self.events.push_back(Event::StreamFailed {
+            stream_id,
+            code,
+            error,
+        });
+        Ok(())
+    }
+
+    /// Next event produced by `receive`
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Next packet to encode and send to the peer
+    pub fn poll_transmit(&mut self) -> Option<Packets> {
+        self.transmit.pop_front()
+    }
+
+    /// Open a stream with the next ID of our half of the ID space. Once every ID was used,
+    /// streams can only be opened on a new connection.
+    pub fn open_stream(&mut self) -> Result<StreamId, NetworkError> {
+        let stream_id = self.next_id.ok_or(NetworkError::StreamIdsExhausted)?;
+        if self.streams.contains_key(&stream_id) {
+            return Err(NetworkError::StreamAlreadyExists(stream_id));
+        }
+        self.streams.insert(stream_id, StreamState::new());
+        self.next_id = stream_id.checked_add(2);
+
+        self.queue_packet(StreamOpen { stream_id });
+        Ok(stream_id)
+    }
+
+    /// Queue as much of `data` as the windows of the peer allow, taking it out of `data`.
+    /// What is left must be sent again once `send_capacity` grows. Empty data is always sent.
+    ///
+    /// Data is split into Stream Data packets that each fit in a frame the peer accepts, so
+    /// credit is only consumed for packets that can be encoded.
+    pub fn send(&mut self, stream_id: StreamId, data: &mut Bytes) -> Result<(), NetworkError> {
+        let mut capacity = self.send_capacity(stream_id)?;
+        if capacity == 0 && !data.is_empty() {
+            return Ok(());
+        }
+
+        let max_chunk = self.max_frame_size as usize - TAG_SIZE - STREAM_DATA_OVERHEAD;
+        loop {
+            let chunk = data.split_to(data.len().min(capacity).min(max_chunk));
+            capacity -= chunk.len();
+            if self.flow_control() {
+                self.send_window.consume(chunk.len());
+                if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.send_window.consume(chunk.len());
+                }
+            }
+            self.queue_packet(StreamData {
+                stream_id,
+                data: chunk,
+            });
+
+            if data.is_empty() || capacity == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Bytes of Stream Data the peer accepts on `stream_id` right now
+    pub fn send_capacity(&self, stream_id: StreamId) -> Result<usize, NetworkError> {
+        let stream = self
+            .streams
+            .get(&stream_id)
+            .filter(|stream| stream.sending)
+            .ok_or(NetworkError::StreamClosed(stream_id))?;
+        if !self.flow_control() {
+            return Ok(usize::MAX);
+        }
+        Ok(stream.send_window.available().min(self.send_window.available()))
+    }
+
+    /// Give back the credit of `length` bytes of `stream_id` read by the application,
+    /// queueing Window Updates once enough was read
+    pub fn release(&mut self, stream_id: StreamId, length: usize) {
+        if !self.flow_control() {
+            return;
+        }
+        // Credit of closed streams was given back to the connection when they closed
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+
+        let length = length.min(stream.unreleased);
+        stream.unreleased -= length;
+        // A peer that finished sending needs no more credit
+        if let Some(increment) = stream.receive_window.release(length)
+            && stream.receiving
+        {
+            self.queue_packet(WindowUpdate {
+                stream_id,
+                increment,
+            });
+        }
+        self.release_connection(length);
+    }
+
+    /// Finish sending on `stream_id`, the peer can still send until it finishes too.
+    /// Finishing a stream again does nothing.
+    pub fn finish(&mut self, stream_id: StreamId) -> Result<(), NetworkError> {
+        let stream = self
+            .streams
+            .get_mut(&stream_id)
+            .ok_or(NetworkError::StreamClosed(stream_id))?;
+        if !stream.sending {
+            return Ok(());
+        }
+        stream.sending = false;
+        if !stream.receiving {
+            self.remove_stream(stream_id);
+        }
+
+        self.queue_packet(StreamClose { stream_id });
+        Ok(())
+    }
+
+    /// Stop using `stream_id` in both directions: finish it when the peer already finished,
+    /// otherwise reset it with `ResetCode::Cancelled` since nothing reads what the peer still sends
+    pub fn close_stream(&mut self, stream_id: StreamId) -> Result<(), NetworkError> {
+        let stream = self
+            .streams
+            .get(&stream_id)
+            .ok_or(NetworkError::StreamClosed(stream_id))?;
+        if stream.receiving {
+            self.reset(stream_id, ResetCode::Cancelled)
+        } else {
+            self.finish(stream_id)
+        }
+    }
+
+    /// End both directions of `stream_id` at once, telling the peer why with `code`.
+    /// What it sends until it receives the reset is dropped.
+    pub fn reset(&mut self, stream_id: StreamId, code: ResetCode) -> Result<(), NetworkError> {
+        if !self.streams.contains_key(&stream_id) {
+            return Err(NetworkError::StreamClosed(stream_id));
+        }
+        self.remove_stream(stream_id);
+
+        self.queue_packet(StreamReset { stream_id, code });
+        Ok(())
+    }
+
+    /// Send a packet on the control stream. A packet whose frame would be larger than the peer
+    /// accepts fails with `NetworkError::FrameTooLarge` and isn't sent.
+    pub fn send_control(&mut self, packet: impl Into<Packets>) -> Result<(), NetworkError> {
+        let packet = packet.into();
+        let mut serialized = BytesMut::new();
+        packet.serialize_into(&mut serialized)?;
+        let length = serialized.len() + TAG_SIZE;
+        if length > self.max_frame_size as usize {
+            return Err(NetworkError::FrameTooLarge {
+                length,
+                max: self.max_frame_size,
+            });
+        }
+
+        self.queue_packet(packet);
+        Ok(())
+    }
+
+    /// Tell the peer the connection is still alive while no stream has traffic
+    pub fn heartbeat(&mut self) {
+        self.queue_packet(Heartbeat::new());
+    }
+
+    /// Switch our sending key to its next generation, and ask the peer to do the same
+    pub fn rekey(&mut self) -> Result<(), NetworkError> {
+        if !self.capabilities.contains(Capabilities::KEY_UPDATE) {
+            return Err(NetworkError::CapabilityNotNegotiated(Capabilities::KEY_UPDATE));
+        }
+        self.queue_packet(KeyUpdate::new(true));
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, packet: Bytes) -> Result<(), NetworkError> {
+        let event = match from_packet_buf(packet)? {
+            Packets::StreamOpen(open) => {
+                // The peer only opens streams from its own half of the ID space
+                if open.stream_id == CONTROL_STREAM_ID || self.role.allocates(open.stream_id) {
+                    return Err(NetworkError::InvalidStreamId(open.stream_id));
+                }
+                if self.streams.contains_key(&open.stream_id) {
+                    return Err(NetworkError::StreamAlreadyExists(open.stream_id));
+                }
+                if open.stream_id <= self.peer_last_id {
+                    return Err(NetworkError::StreamIdReused(open.stream_id));
+                }
+                self.peer_last_id = open.stream_id;
+                self.streams.insert(open.stream_id, StreamState::new());
+                Event::StreamOpened(open.stream_id)
+            }
+            Packets::StreamClose(close) => return self.handle_stream_close(close),
+            Packets::StreamData(data) => return self.handle_stream_data(data),
+            Packets::StreamReset(reset) => return self.handle_stream_reset(reset),
+            Packets::WindowUpdate(update) => return self.handle_window_update(update),
+            Packets::StreamError(error) => Event::StreamError {
+                stream_id: error.stream_id,
+                error: error.error,
+            },
+            Packets::KeyUpdate(update) => return self.handle_key_update(update),
+            // Receiving its frame is all it is for
+            Packets::Heartbeat(_) => return Ok(()),
+            Packets::NewSessionTicket(ticket) => {
+                if ticket.stream_id != CONTROL_STREAM_ID {
+                    return Err(NetworkError::UnexpectedPacket);
+                }
+                Event::SessionTicket {
+                    lifetime: Duration::from_secs(ticket.lifetime.into()),
+                    ticket: ticket.ticket,
+                }
+            }
+            _ => Event::UnexpectedPacket,
+        };
+
+        self.events.push_back(event);
+        Ok(())
+    }
+
+    fn handle_stream_data(&mut self, data: StreamData) -> Result<(), NetworkError> {
+        let length = data.data.len();
+        let flow_control = self.flow_control();
+        if flow_control {
+            self.receive_window.receive(CONTROL_STREAM_ID, length)?;
+        }
+
+        let Some(stream) = self.streams.get_mut(&data.stream_id) else {
+            // Nobody reads it, its credit is given back right away
+            self.release_connection(length);
+            self.events.push_back(Event::UnknownStream(data.stream_id));
+            return Ok(());
+        };
+        let accepted = if !stream.receiving {
+            Err(NetworkError::StreamClosed(data.stream_id))
+        } else if flow_control {
+            stream
+                .receive_window
+                .receive(data.stream_id, length)
+                .map(|()| stream.unreleased += length)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = accepted {
+            // The stream is reset, its credit is given back right away
+            self.release_connection(length);
+            return Err(e);
+        }
+
+        self.events.push_back(Event::StreamData {
+            stream_id: data.stream_id,
+            data: data.data,
+        });
+        Ok(())
+    }
+
+    fn handle_stream_close(&mut self, close: StreamClose) -> Result<(), NetworkError> {
+        let Some(stream) = self.streams.get_mut(&close.stream_id) else {
+            self.events.push_back(Event::UnknownStream(close.stream_id));
+            return Ok(());
+        };
+        if !stream.receiving {
+            return Err(NetworkError::StreamClosed(close.stream_id));
+        }
+        stream.receiving = false;
+        if !stream.sending {
+            self.remove_stream(close.stream_id);
+        }
+
+        self.events.push_back(Event::StreamFinished(close.stream_id));
+        Ok(())
+    }
+
+    fn handle_stream_reset(&mut self, reset: StreamReset) -> Result<(), NetworkError> {
+        // Both peers may reset a stream at once, or the stream ended while it was in flight
+        if !self.streams.contains_key(&reset.stream_id) {
+            return Ok(());
+        }
+        self.remove_stream(reset.stream_id);
+
+        self.events.push_back(Event::StreamReset {
+            stream_id: reset.stream_id,
+            code: reset.code,
+        });
+        Ok(())
+    }
+
+    fn handle_window_update(&mut self, update: WindowUpdate) -> Result<(), NetworkError> {
+        if !self.flow_control() {
+            return Err(NetworkError::UnexpectedPacket);
+        }
+
+        if update.stream_id == CONTROL_STREAM_ID {
+            self.send_window.grant(update.stream_id, update.increment)?;
+        } else if let Some(stream) = self.streams.get_mut(&update.stream_id) {
+            stream.send_window.grant(update.stream_id, update.increment)?;
+        } else {
+            // The stream was closed while the update was in flight
+            return Ok(());
+        }
+
+        self.events.push_back(Event::WindowUpdated(update.stream_id));
+        Ok(())
+    }
+
+    fn handle_key_update(&mut self, update: KeyUpdate) -> Result<(), NetworkError> {
+        if update.stream_id != CONTROL_STREAM_ID
+            || !self.capabilities.contains(Capabilities::KEY_UPDATE)
+        {
+            return Err(NetworkError::UnexpectedPacket);
+        }
+
+        self.peer_generation += 1;
+        self.events.push_back(Event::PeerKeyUpdated {
+            generation: self.peer_generation,
+        });
+
+        if update.update_requested {
+            self.queue_packet(KeyUpdate::new(false));
+        }
+        Ok(())
+    }
+
+    fn remove_stream(&mut self, stream_id: StreamId) {
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            self.release_connection(stream.unreleased);
+        }
+    }
+
+    fn release_connection(&mut self, length: usize) {
+        if !self.flow_control() {
+            return;
+        }
+        if let Some(increment) = self.receive_window.release(length) {
+            self.queue_packet(WindowUpdate {
+                stream_id: CONTROL_STREAM_ID,
+                increment,
+            });
+        }
+    }
+
+    fn flow_control(&self) -> bool {
+        self.capabilities.contains(Capabilities::FLOW_CONTROL)
+    }
+
+    fn queue_packet(&mut self, packet: impl Into<Packets>) {
+        self.transmit.push_back(packet.into());
+    }
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            sending: true,
+            receiving: true,
+            send_window: SendWindow::new(INITIAL_STREAM_WINDOW),
+            receive_window: ReceiveWindow::new(INITIAL_STREAM_WINDOW),
+            unreleased: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{DEFAULT_MAX_FRAME_SIZE, MIN_FRAME_SIZE};
+    use crate::packets::StreamError;
+
+    fn pair() -> (Multiplexer, Multiplexer) {
+        let capabilities = Capabilities::FLOW_CONTROL;
+        (
+            Multiplexer::new(Role::Agent, capabilities, DEFAULT_MAX_FRAME_SIZE),
+            Multiplexer::new(Role::Server, capabilities, DEFAULT_MAX_FRAME_SIZE),
+        )
+    }
+
+    fn encode(packet: impl Into<Packets>) -> Bytes {
+        let mut buffer = BytesMut::new();
+        packet.into().serialize_into(&mut buffer).unwrap();
+        buffer.freeze()
+    }
+
+    /// Hand every packet `from` queued to `to`
+    fn deliver(from: &mut Multiplexer, to: &mut Multiplexer) -> Result<(), NetworkError> {
+        while let Some(packet) = from.poll_transmit() {
+            to.receive(encode(packet))?;
+        }
+        Ok(())
+    }
+
+    fn events(multiplexer: &mut Multiplexer) -> Vec<Event> {
+        std::iter::from_fn(|| multiplexer.poll_event()).collect()
+    }
+
+    fn transmitted(multiplexer: &mut Multiplexer) -> Vec<Packets> {
+        std::iter::from_fn(|| multiplexer.poll_transmit()).collect()
+    }
+
+    /// Stream opened by the agent, known to both sides
+    fn open(agent: &mut Multiplexer, server: &mut Multiplexer) -> StreamId {
+        let stream_id = agent.open_stream().unwrap();
+        deliver(agent, server).unwrap();
+        assert!(matches!(events(server)[..], [Event::StreamOpened(id)] if id == stream_id));
+        stream_id
+    }
+
+    #[test]
+    fn stream_ids_follow_role_parity() {
+        let (mut agent, mut server) = pair();
+
+        assert_eq!(agent.open_stream().unwrap(), 1);
+        assert_eq!(agent.open_stream().unwrap(), 3);
+        assert_eq!(server.open_stream().unwrap(), 2);
+        assert_eq!(server.open_stream().unwrap(), 4);
+
+        // Both opened streams at once without colliding
+        deliver(&mut agent, &mut server).unwrap();
+        deliver(&mut server, &mut agent).unwrap();
+        assert_eq!(events(&mut agent).len(), 2);
+        assert_eq!(events(&mut server).len(), 2);
+    }
+
+    #[test]
+    fn stream_open_in_our_half_is_fatal() {
+        let (_, mut server) = pair();
+
+        let ours = server.receive(encode(StreamOpen { stream_id: 2 }));
+        let control = server.receive(encode(StreamOpen { stream_id: 0 }));
+
+        assert!(matches!(ours, Err(NetworkError::InvalidStreamId(2))));
+        assert!(matches!(control, Err(NetworkError::InvalidStreamId(0))));
+        assert!(server.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn stream_open_twice_resets_the_stream() {
+        let (mut agent, mut server) = pair();
+        let stream_id = open(&mut agent, &mut server);
+
+        server.receive(encode(StreamOpen { stream_id })).unwrap();
+
+        assert!(matches!(
+            events(&mut server)[..],
+            [Event::StreamFailed { code: ResetCode::ProtocolError, .. }]
+        ));
+        assert!(matches!(
+            transmitted(&mut server)[..],
+            [Packets::StreamReset(StreamReset { code: ResetCode::ProtocolError, .. })]
+        ));
+    }
+
+    #[test]
+    fn closed_stream_ids_are_not_reopened() {
+        let (mut agent, mut server) = pair();
+        let first = open(&mut agent, &mut server);
+        let second = open(&mut agent, &mut server);
+        server.reset(first, ResetCode::Cancelled).unwrap();
+        transmitted(&mut server);
+
+        server.receive(encode(StreamOpen { stream_id: first })).unwrap();
+        server.receive(encode(StreamOpen { stream_id: second + 4 })).unwrap();
+        server.receive(encode(StreamOpen { stream_id: second + 2 })).unwrap();
+
+        let events = events(&mut server);
+        assert!(matches!(
+            events[..],
+            [
+                Event::StreamFailed { stream_id, code: ResetCode::ProtocolError, .. },
+                Event::StreamOpened(_),
+                Event::StreamFailed { code: ResetCode::ProtocolError, .. },
+            ] if stream_id == first
+        ));
+        assert!(!server.streams.contains_key(&first));
+        assert!(server.streams.contains_key(&second));
+    }
+
+    #[test]
+    fn ids_run_out_instead_of_wrapping() {
+        let (mut agent, _) = pair();
+        agent.next_id = Some(u32::MAX);
+
+        assert_eq!(agent.open_stream().unwrap(), u32::MAX);
+        assert!(matches!(agent.open_stream(), Err(NetworkError::StreamIdsExhausted)));
+    }
+
+    #[test]
+    fn half_closed_stream_keeps_receiving() {
+        let (mut agent, mut server) = pair();
+        let stream_id = open(&mut agent, &mut server);
+
+        agent.finish(stream_id).unwrap();
+        deliver(&mut agent, &mut server).unwrap();
+        assert!(matches!(events(&mut server)[..], [Event::StreamFinished(id)] if id == stream_id));
+        assert!(matches!(
+            agent.send(stream_id, &mut Bytes::from_static(b"late")),
+            Err(NetworkError::StreamClosed(_))
+        ));
+
+        // The other direction is still open
+        server.send(stream_id, &mut Bytes::from_static(b"reply")).unwrap();
+        deliver(&mut server, &mut agent).unwrap();
+        assert!(matches!(
+            &events(&mut agent)[..],
+            [Event::StreamData { data, .. }] if data == &b"reply"[..]
+        ));
+
+        // Forgotten once both directions ended
+        server.finish(stream_id).unwrap();
+        deliver(&mut server, &mut agent).unwrap();
+        assert!(matches!(events(&mut agent)[..], [Event::StreamFinished(_)]));
+        assert!(agent.streams.is_empty() && server.streams.is_empty());
+    }
+
+    #[test]
+    fn data_after_finish_resets_the_stream() {
+        let (mut agent, mut server) = pair();
+        let stream_id = open(&mut agent, &mut server);
+        agent.finish(stream_id).unwrap();
+        deliver(&mut agent, &mut server).unwrap();
+        events(&mut server);
+
+        let data = StreamData {
+            stream_id,
+            data: Bytes::from_static(b"late"),
+        };
+        server.receive(encode(data)).unwrap();
+
+        assert!(matches!(
+            events(&mut server)[..],
+            [Event::StreamFailed { code: ResetCode::StreamClosed, .. }]
+        ));
+        assert!(!server.streams.contains_key(&stream_id));
+    }
+
+    #[test]
+    fn reset_ends_both_directions() {
+        let (mut agent, mut server) = pair();
+        let stream_id = open(&mut agent, &mut server);
+
+        server.reset(stream_id, ResetCode::Cancelled).unwrap();
+        deliver(&mut server, &mut agent).unwrap();
+
+        assert!(matches!(
+            events(&mut agent)[..],
+            [Event::StreamReset { code: ResetCode::Cancelled, .. }]
+        ));
+        assert!(matches!(
+            agent.send(stream_id, &mut Bytes::from_static(b"gone")),
+            Err(NetworkError::StreamClosed(_))
+        ));
+        // A reset crossing ours is ignored
+        server.receive(encode(StreamReset { stream_id, code: ResetCode::Cancelled })).unwrap();
+        assert!(events(&mut server).is_empty());
+    }
+
+    #[test]
+    fn close_stream_resets_only_while_receiving() {
+        let (mut agent, mut server) = pair();
+        let receiving = open(&mut agent, &mut server);
+        let finished = open(&mut agent, &mut server);
+        server.finish(finished).unwrap();
+        deliver(&mut server, &mut agent).unwrap();
+        events(&mut agent);
+
+        agent.close_stream(receiving).unwrap();
+        agent.close_stream(finished).unwrap();
+
+        assert!(matches!(
+            transmitted(&mut agent)[..],
+            [
+                Packets::StreamReset(StreamReset { code: ResetCode::Cancelled, .. }),
+                Packets::StreamClose(_),
+            ]
+        ));
+        assert!(agent.streams.is_empty());
+    }
+
+    #[test]
+    fn send_is_bounded_by_the_stream_window() {
+        let (mut agent, mut server) = pair();
+        let stream_id = open(&mut agent, &mut server);
+
+        let window = INITIAL_STREAM_WINDOW as usize;
+        let mut data = Bytes::from(vec![0; window + 10]);
+        agent.send(stream_id, &mut data).unwrap();
+
+        assert_eq!(data.len(), 10);
+        assert_eq!(agent.send_capacity(stream_id).unwrap(), 0);
+        // Nothing is queued without credit
+        agent.send(stream_id, &mut data).unwrap();
+        assert_eq!(data.len(), 10);
+
+        deliver(&mut agent, &mut server).unwrap();
+        let received: usize = events(&mut server)
+            .iter()
+            .map(|event| match event {
+                Event::StreamData { data, .. } => data.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(received, window);
+
+        // Credit comes back once half the window was read
+        server.release(stream_id, window / 2 - 1);
+        assert!(server.poll_transmit().is_none());
+        server.release(stream_id, 1);
+        deliver(&mut server, &mut agent).unwrap();
+        assert!(matches!(events(&mut agent)[..], [Event::WindowUpdated(id)] if id == stream_id));
+        assert_eq!(agent.send_capacity(stream_id).unwrap(), window / 2);
+    }
+
+    #[test]
+    fn connection_window_is_shared_by_streams() {
+        let (mut agent, mut server) = pair();
+        let streams = [0; 5].map(|_| open(&mut agent, &mut server));
+
+        let mut sent = 0;
+        for stream_id in streams {
+            let mut data = Bytes::from(vec![0; INITIAL_STREAM_WINDOW as usize]);
+            agent.send(stream_id, &mut data).unwrap();
+            sent += INITIAL_STREAM_WINDOW as usize - data.len();
+        }
+
+        assert_eq!(sent, INITIAL_CONNECTION_WINDOW as usize);
+        assert_eq!(agent.send_capacity(streams[4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn data_past_the_window_resets_the_stream() {
+        let (mut agent, mut server) = pair();
+        let stream_id = open(&mut agent, &mut server);
+
+        let data = StreamData {
+            stream_id,
+            data: Bytes::from(vec![0; INITIAL_STREAM_WINDOW as usize + 1]),
+        };
+        server.receive(encode(data)).unwrap();
+
+        assert!(matches!(
+            events(&mut server)[..],
+            [Event::StreamFailed { code: ResetCode::FlowControlError, .. }]
+        ));
+        assert!(matches!(
+            transmitted(&mut server)[..],
+            [Packets::StreamReset(StreamReset { code: ResetCode::FlowControlError, .. })]
+        ));
+    }
+
+    #[test]
+    fn window_update_past_u32_max_is_a_violation() {
+        let (mut agent, mut server) = pair();
+        let stream_id = open(&mut agent, &mut server);
+
+        let update = WindowUpdate {
+            stream_id,
+            increment: u32::MAX,
+        };
+        agent.receive(encode(update)).unwrap();
+        assert!(matches!(
+            events(&mut agent)[..],
+            [Event::StreamFailed { code: ResetCode::FlowControlError, .. }]
+        ));
+
+        let update = WindowUpdate {
+            stream_id: CONTROL_STREAM_ID,
+            increment: u32::MAX,
+        };
+        assert!(matches!(
+            agent.receive(encode(update)),
+            Err(NetworkError::FlowControlViolation(CONTROL_STREAM_ID))
+        ));
+    }
+
+    #[test]
+    fn send_splits_data_to_fit_frames() {
+        let capabilities = Capabilities::FLOW_CONTROL;
+        let mut agent = Multiplexer::new(Role::Agent, capabilities, MIN_FRAME_SIZE);
+        let stream_id = agent.open_stream().unwrap();
+        agent.poll_transmit();
+
+        let mut data = Bytes::from(vec![0; 10_000]);
+        agent.send(stream_id, &mut data).unwrap();
+
+        let max_chunk = MIN_FRAME_SIZE as usize - TAG_SIZE - STREAM_DATA_OVERHEAD;
+        let chunks = transmitted(&mut agent)
+            .into_iter()
+            .map(|packet| match packet {
+                Packets::StreamData(data) => data.data.len(),
+                other => panic!("expected Stream Data, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert!(data.is_empty());
+        assert_eq!(chunks.iter().sum::<usize>(), 10_000);
+        assert!(chunks.iter().all(|chunk| *chunk <= max_chunk));
+        // Every chunk, serialized, fits in a frame
+        let largest = encode(StreamData {
+            stream_id,
+            data: Bytes::from(vec![0; max_chunk]),
+        });
+        assert!(largest.len() + TAG_SIZE <= MIN_FRAME_SIZE as usize);
+    }
+
+    #[test]
+    fn oversize_control_packet_is_refused() {
+        let capabilities = Capabilities::FLOW_CONTROL;
+        let mut agent = Multiplexer::new(Role::Agent, capabilities, MIN_FRAME_SIZE);
+
+        let error = StreamError {
+            stream_id: CONTROL_STREAM_ID,
+            error: "x".repeat(MIN_FRAME_SIZE as usize),
+        };
+        assert!(matches!(
+            agent.send_control(error),
+            Err(NetworkError::FrameTooLarge { max: MIN_FRAME_SIZE, .. })
+        ));
+        assert!(agent.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn heartbeat_is_silent() {
+        let (mut agent, mut server) = pair();
+
+        agent.heartbeat();
+        deliver(&mut agent, &mut server).unwrap();
+
+        assert!(events(&mut server).is_empty());
+    }
+
+    #[test]
+    fn rekey_is_answered_by_the_peer() {
+        let capabilities = Capabilities::KEY_UPDATE;
+        let mut agent = Multiplexer::new(Role::Agent, capabilities, DEFAULT_MAX_FRAME_SIZE);
+        let mut server = Multiplexer::new(Role::Server, capabilities, DEFAULT_MAX_FRAME_SIZE);
+
+        agent.rekey().unwrap();
+        deliver(&mut agent, &mut server).unwrap();
+        assert!(matches!(events(&mut server)[..], [Event::PeerKeyUpdated { generation: 1 }]));
+
+        // The answer doesn't ask for another update, which would never end
+        assert!(matches!(
+            transmitted(&mut server)[..],
+            [Packets::KeyUpdate(KeyUpdate { update_requested: false, .. })]
+        ));
+        server.receive(encode(KeyUpdate::new(false))).unwrap();
+        assert!(matches!(events(&mut server)[..], [Event::PeerKeyUpdated { generation: 2 }]));
+        assert!(server.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn rekey_needs_key_updates() {
+        let (mut agent, mut server) = pair();
+
+        assert!(matches!(
+            agent.rekey(),
+            Err(NetworkError::CapabilityNotNegotiated(Capabilities::KEY_UPDATE))
+        ));
+        assert!(matches!(
+            server.receive(encode(KeyUpdate::new(true))),
+            Err(NetworkError::UnexpectedPacket)
+        ));
+    }
+}
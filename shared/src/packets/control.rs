@@ -0,0 +1,53 @@
+use bincode::{Decode, Encode};
+use derive::Packet;
+
+use crate::multiplexing::{CONTROL_STREAM_ID, StreamId};
+
+/// Control packet announcing that every following frame from the sender uses its next key
+/// generation. When `update_requested` is set the receiver answers with its own key update.
+#[derive(Debug, Encode, Decode, Packet)]
+#[packet(code = 0x09)]
+pub struct KeyUpdate {
+    pub stream_id: StreamId,
+    pub update_requested: bool,
+}
+
+impl KeyUpdate {
+    pub fn new(update_requested: bool) -> Self {
+        KeyUpdate {
+            stream_id: CONTROL_STREAM_ID,
+            update_requested,
+        }
+    }
+}
+
+/// Control packet sent by the server after the handshake, the agent presents the ticket in its
+/// next Encryption Request to resume the session with an abbreviated handshake
+#[derive(Debug, Encode, Decode, Packet)]
+#[packet(code = 0x0B)]
+pub struct NewSessionTicket {
+    pub stream_id: StreamId,
+    /// Seconds the ticket can be used for
+    pub lifetime: u32,
+    /// Opaque to the agent, encrypted with a key only the server knows
+    pub ticket: Vec<u8>,
+}
+
+impl NewSessionTicket {
+    pub fn new(lifetime: u32, ticket: Vec<u8>) -> Self {
+        NewSessionTicket {
+            stream_id: CONTROL_STREAM_ID,
+            lifetime,
+            ticket,
+        }
+    }
+}
+
+/// Control packet granting the receiver `increment` more bytes of Stream Data on `stream_id`,
+/// or on the whole connection when sent on the control stream
+#[derive(Debug, Encode, Decode, Packet)]
+#[packet(code = 0x0C)]
+pub struct WindowUpdate {
+    pub stream_id: StreamId,
+    pub increment: u32,
+}
@@ -1,37 +1,149 @@
 use bincode::{self, Decode, Encode};
 use derive::Packet;
 
+use crate::{
+    encryption::CipherSuite,
+    handshake::{Capabilities, HandshakeMode, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, RejectCode},
+};
+
+/// First packet of the handshake, the version range must stay the first fields in every version,
+/// see `handshake::peek_version_range`
 #[derive(Debug, Encode, Decode, Packet)]
 #[packet(code = 0x01)]
 pub struct EncryptionRequest {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub capabilities: Capabilities,
     pub key: [u8; 32],
     pub verify_token: u64,
+    pub identity_key: [u8; 32],
+    pub mode: HandshakeMode,
+    /// Bitmask of the cipher suites the agent supports, see `CipherSuite::bit`
+    pub cipher_suites: u8,
+    /// ML-KEM encapsulation key, when the agent offers the `HYBRID_KEM` capability
+    pub kem_key: Option<Vec<u8>>,
+    /// Session ticket of a previous connection, to resume it with an abbreviated handshake
+    pub ticket: Option<Vec<u8>>,
+    /// Largest frame the agent accepts, see `framing::negotiate_frame_size`
+    pub max_frame_size: u32,
 }
 
 impl EncryptionRequest {
-    pub const PACKET_SIZE: usize = 42;
-    
-    pub fn new(key: [u8; 32], verify_token: u64) -> Self {
-        EncryptionRequest { key, verify_token }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        capabilities: Capabilities,
+        key: [u8; 32],
+        verify_token: u64,
+        identity_key: [u8; 32],
+        mode: HandshakeMode,
+        cipher_suites: u8,
+        kem_key: Option<Vec<u8>>,
+        ticket: Option<Vec<u8>>,
+        max_frame_size: u32,
+    ) -> Self {
+        EncryptionRequest {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities,
+            key,
+            verify_token,
+            identity_key,
+            mode,
+            cipher_suites,
+            kem_key,
+            ticket,
+            max_frame_size,
+        }
     }
 }
 
 #[derive(Debug, Encode, Decode, Packet)]
 #[packet(code = 0x02)]
 pub struct EncryptionResponse {
+    pub version: u16,
+    pub capabilities: Capabilities,
     pub key: [u8; 32],
     pub nonce: [u8; 12],
     pub verify_token: [u8; 24],
+    pub identity_key: [u8; 32],
+    pub signature: [u8; 64],
+    pub mode: HandshakeMode,
+    pub cipher_suite: CipherSuite,
+    /// ML-KEM ciphertext, when the `HYBRID_KEM` capability is enabled
+    pub kem_ciphertext: Option<Vec<u8>>,
+    /// Whether the server accepted the session ticket, the signatures are then left empty
+    pub resumed: bool,
+    /// Largest frame either peer may send on this connection
+    pub max_frame_size: u32,
 }
 
 impl EncryptionResponse {
-    pub const PACKET_SIZE: usize = 69;
-    
-    pub fn new(key: [u8; 32], nonce: [u8; 12], verify_token: [u8; 24]) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        version: u16,
+        capabilities: Capabilities,
+        key: [u8; 32],
+        nonce: [u8; 12],
+        verify_token: [u8; 24],
+        identity_key: [u8; 32],
+        signature: [u8; 64],
+        mode: HandshakeMode,
+        cipher_suite: CipherSuite,
+        kem_ciphertext: Option<Vec<u8>>,
+        resumed: bool,
+        max_frame_size: u32,
+    ) -> Self {
         EncryptionResponse {
+            version,
+            capabilities,
             key,
             nonce,
             verify_token,
+            identity_key,
+            signature,
+            mode,
+            cipher_suite,
+            kem_ciphertext,
+            resumed,
+            max_frame_size,
+        }
+    }
+}
+
+/// Packet sent by the agent to prove it owns the identity key of its Encryption Request,
+/// and that it derived the same session secret as the server
+#[derive(Debug, Encode, Decode, Packet)]
+#[packet(code = 0x08)]
+pub struct AgentAuthentication {
+    pub signature: [u8; 64],
+    pub nonce: [u8; 12],
+    pub verify_data: [u8; 48],
+}
+
+impl AgentAuthentication {
+    pub fn new(signature: [u8; 64], nonce: [u8; 12], verify_data: [u8; 48]) -> Self {
+        AgentAuthentication {
+            signature,
+            nonce,
+            verify_data,
+        }
+    }
+}
+
+/// Packet sent by the server instead of an Encryption Response when it refuses the handshake,
+/// the connection is closed right after
+#[derive(Debug, Encode, Decode, Packet)]
+#[packet(code = 0x0A)]
+pub struct HandshakeReject {
+    pub code: RejectCode,
+    pub message: String,
+}
+
+impl HandshakeReject {
+    pub fn new(code: RejectCode, message: impl Into<String>) -> Self {
+        HandshakeReject {
+            code,
+            message: message.into(),
         }
     }
 }
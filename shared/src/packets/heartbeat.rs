@@ -1,6 +1,8 @@
 use bincode::{Decode, Encode};
 use crate::Packet;
 
+/// Packet sent by the agent at a regular interval so an otherwise quiet connection isn't dropped
+/// as idle, it carries nothing else
 #[derive(Debug, Encode, Decode, Packet)]
 #[packet(code = 0x07)]
 pub struct Heartbeat {
@@ -1,9 +1,11 @@
+mod control;
 mod encryption;
 mod heartbeat;
 mod packet;
 mod stream;
 
-pub use encryption::{EncryptionRequest, EncryptionResponse};
+pub use control::{KeyUpdate, NewSessionTicket, WindowUpdate};
+pub use encryption::{AgentAuthentication, EncryptionRequest, EncryptionResponse, HandshakeReject};
 pub use heartbeat::Heartbeat;
-pub use packet::{PacketError, Packet, Packets, from_packet_bytes};
-pub use stream::{StreamOpen, StreamClose, StreamData, StreamError};
+pub use packet::{MAX_DECODE_SIZE, PacketError, decode_bounded, Packet, Packets, from_packet_buf, from_packet_bytes, packet_stream_id};
+pub use stream::{STREAM_DATA_OVERHEAD, StreamOpen, StreamClose, StreamData, StreamError, StreamReset};
@@ -1,6 +1,10 @@
+use bincode::{Decode, config};
+use bytes::{Bytes, BytesMut};
 use thiserror::Error;
 
-use super::{EncryptionRequest, EncryptionResponse, Heartbeat, StreamOpen, StreamClose, StreamData, StreamError};
+use crate::framing::MAX_FRAME_SIZE;
+
+use super::{AgentAuthentication, EncryptionRequest, EncryptionResponse, HandshakeReject, Heartbeat, KeyUpdate, NewSessionTicket, StreamOpen, StreamClose, StreamData, StreamError, StreamReset, WindowUpdate};
 
 #[derive(Debug)]
 pub enum Packets {
@@ -10,7 +14,41 @@ pub enum Packets {
     StreamClose(StreamClose),
     StreamData(StreamData),
     StreamError(StreamError),
-    Heartbeat(Heartbeat)
+    Heartbeat(Heartbeat),
+    AgentAuthentication(AgentAuthentication),
+    KeyUpdate(KeyUpdate),
+    HandshakeReject(HandshakeReject),
+    NewSessionTicket(NewSessionTicket),
+    WindowUpdate(WindowUpdate),
+    StreamReset(StreamReset),
+}
+
+/// Bytes a packet may claim while being decoded, so a forged `Vec` or `String` length
+/// can't make the decoder allocate more than the largest frame could hold
+pub const MAX_DECODE_SIZE: usize = MAX_FRAME_SIZE as usize;
+
+/// Bytes integers may claim beyond their encoding while being decoded, they claim their full
+/// size even when their varint takes a single byte
+const DECODE_SLACK: usize = 256;
+
+/// Decode a `T` from `data`, claiming about as many bytes as `data` holds, so a forged `Vec` or
+/// `String` length can't make the decoder allocate much more than what was received. Bincode
+/// only takes a constant limit, `data.len()` and the slack are rounded up to a power of two, at
+/// most `MAX_DECODE_SIZE`.
+pub fn decode_bounded<T: Decode<()>>(data: &[u8]) -> Result<(T, usize), PacketError> {
+    macro_rules! decode_with_limit {
+        ($($bits:literal)*) => {
+            match (data.len() + DECODE_SLACK).next_power_of_two().trailing_zeros() {
+                $(bits if bits <= $bits => {
+                    bincode::decode_from_slice(data, config::standard().with_limit::<{ 1 << $bits }>())
+                })*
+                _ => bincode::decode_from_slice(data, config::standard().with_limit::<MAX_DECODE_SIZE>()),
+            }
+        };
+    }
+
+    decode_with_limit!(9 10 11 12 13 14 15 16 17 18 19 20 21 22 23)
+        .map_err(|e| PacketError::DecodingError(e.to_string()))
 }
 
 #[derive(Error, Debug)]
@@ -25,15 +63,87 @@ pub enum PacketError {
 
 pub trait Packet {
     fn serialize(&self) -> Result<Vec<u8>, PacketError>;
+    /// Append the packet to `dst`, code included, without an intermediate buffer
+    fn serialize_into(&self, dst: &mut BytesMut) -> Result<(), PacketError>;
     fn deserialize(data: &[u8]) -> Result<Self, PacketError>
     where
         Self: Sized;
     fn packet_code() -> u8;
 }
 
+macro_rules! impl_packets {
+    ($($packet:ident),* $(,)?) => {
+        impl Packets {
+            /// Append the packet to `dst`, code included, without an intermediate buffer
+            pub fn serialize_into(&self, dst: &mut BytesMut) -> Result<(), PacketError> {
+                match self {
+                    $(Packets::$packet(packet) => packet.serialize_into(dst),)*
+                }
+            }
+        }
+
+        $(
+            impl From<$packet> for Packets {
+                fn from(packet: $packet) -> Self {
+                    Packets::$packet(packet)
+                }
+            }
+        )*
+    };
+}
+
+impl_packets!(
+    EncryptionRequest,
+    EncryptionResponse,
+    StreamOpen,
+    StreamClose,
+    StreamData,
+    StreamError,
+    Heartbeat,
+    AgentAuthentication,
+    KeyUpdate,
+    HandshakeReject,
+    NewSessionTicket,
+    WindowUpdate,
+    StreamReset,
+);
+
+/// Decode a packet received in a shared buffer.
+/// Stream Data payloads are sliced out of `packet` instead of being copied.
+pub fn from_packet_buf(packet: Bytes) -> Result<Packets, PacketError> {
+    match packet.first() {
+        Some(&code) if code == StreamData::packet_code() => {
+            Ok(Packets::StreamData(StreamData::from_packet_buf(packet)?))
+        }
+        _ => from_packet_bytes(&packet),
+    }
+}
+
+/// Stream a packet is sent on, read without decoding the rest of it so even a malformed packet
+/// can be traced back to its stream. Every packet sent after the handshake, but Heartbeat, starts
+/// with it, unknown packets included. `None` for packets that aren't sent on a stream.
+pub fn packet_stream_id(packet: &[u8]) -> Option<u32> {
+    let (&code, body) = packet.split_first()?;
+    let unscoped = [
+        EncryptionRequest::packet_code(),
+        EncryptionResponse::packet_code(),
+        AgentAuthentication::packet_code(),
+        HandshakeReject::packet_code(),
+        Heartbeat::packet_code(),
+    ];
+    if unscoped.contains(&code) {
+        return None;
+    }
+
+    bincode::decode_from_slice(body, bincode::config::standard())
+        .ok()
+        .map(|(stream_id, _)| stream_id)
+}
+
 pub fn from_packet_bytes(data: &[u8]) -> Result<Packets, PacketError> {
-    let packet_code = data[0];
-    let data = &data[1..];
+    let Some((&packet_code, data)) = data.split_first() else {
+        return Err(PacketError::DecodingError("empty packet".to_string()));
+    };
     match packet_code {
         0x01 => Ok(Packets::EncryptionRequest(
             EncryptionRequest::deserialize(data)?
@@ -56,6 +166,55 @@ pub fn from_packet_bytes(data: &[u8]) -> Result<Packets, PacketError> {
         0x07 => Ok(Packets::Heartbeat(
             Heartbeat::deserialize(data)?
         )),
+        0x08 => Ok(Packets::AgentAuthentication(
+            AgentAuthentication::deserialize(data)?
+        )),
+        0x09 => Ok(Packets::KeyUpdate(
+            KeyUpdate::deserialize(data)?
+        )),
+        0x0A => Ok(Packets::HandshakeReject(
+            HandshakeReject::deserialize(data)?
+        )),
+        0x0B => Ok(Packets::NewSessionTicket(
+            NewSessionTicket::deserialize(data)?
+        )),
+        0x0C => Ok(Packets::WindowUpdate(
+            WindowUpdate::deserialize(data)?
+        )),
+        0x0D => Ok(Packets::StreamReset(
+            StreamReset::deserialize(data)?
+        )),
         _ => Err(PacketError::UnknownPacket(packet_code.to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding_claims_no_more_than_the_packet_holds() {
+        // Stream Error on stream 1 claiming a message of 1 MiB, in a packet of a few bytes
+        let mut packet = vec![StreamError::packet_code(), 1, 0xFC];
+        packet.extend_from_slice(&(1u32 << 20).to_le_bytes());
+        packet.extend_from_slice(b"short");
+
+        let error = from_packet_bytes(&packet).unwrap_err();
+
+        assert!(matches!(error, PacketError::DecodingError(e) if e.contains("LimitExceeded")));
+    }
+
+    #[test]
+    fn packets_of_any_length_decode() {
+        for length in [0, 1, 240, 250, 251, 252, 253, 254, 255, 256, 512, 4096, 70_000] {
+            let packet = StreamError {
+                stream_id: 1,
+                error: "e".repeat(length),
+            };
+
+            let decoded = from_packet_bytes(&packet.serialize().unwrap()).unwrap();
+
+            assert!(matches!(decoded, Packets::StreamError(error) if error.error.len() == length));
+        }
+    }
+}
@@ -1,6 +1,13 @@
-use bincode::{Decode, Encode};
+use bincode::de::{BorrowDecoder, Decoder};
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{BorrowDecode, Decode, Encode};
+use bytes::Bytes;
 use derive::Packet;
 
+use super::{PacketError, decode_bounded};
+use crate::multiplexing::ResetCode;
+
 /// Packet sent to open a new stream
 #[derive(Debug, Encode, Decode, Packet)]
 #[packet(code = 0x03)]
@@ -15,12 +22,19 @@ pub struct StreamClose {
     pub stream_id: u32,
 }
 
-/// Packet containing data for a specific stream
-#[derive(Debug, Encode, Decode, Packet)]
+/// Largest size of a serialized Stream Data packet besides its data: the packet code, then the
+/// stream ID and the data length as bincode varints of at most 5 and 9 bytes
+pub const STREAM_DATA_OVERHEAD: usize = 1 + 5 + 9;
+
+/// Packet containing data for a specific stream.
+///
+/// `data` is encoded like a `Vec<u8>`, a varint length followed by the bytes, and is shared
+/// instead of copied when the packet is decoded with `from_packet_buf`.
+#[derive(Debug, Packet)]
 #[packet(code = 0x05)]
 pub struct StreamData {
     pub stream_id: u32,
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 /// Packet indicating an error on a specific stream
@@ -30,3 +44,57 @@ pub struct StreamError {
     pub stream_id: u32,
     pub error: String,
 }
+
+/// Packet abruptly ending both directions of a stream, data in flight on it is dropped
+#[derive(Debug, Encode, Decode, Packet)]
+#[packet(code = 0x0D)]
+pub struct StreamReset {
+    pub stream_id: u32,
+    pub code: ResetCode,
+}
+
+impl StreamData {
+    /// Decode a Stream Data packet, code included, slicing `data` out of `packet`
+    pub fn from_packet_buf(packet: Bytes) -> Result<Self, PacketError> {
+        let body = packet.get(1..).unwrap_or_default();
+        let ((stream_id, length), prefix): ((u32, u64), usize) = decode_bounded(body)?;
+
+        let start = 1 + prefix;
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|length| start.checked_add(length))
+            .filter(|end| *end <= packet.len())
+            .ok_or_else(|| {
+                PacketError::DecodingError(format!("stream data of {length} bytes exceeds the packet"))
+            })?;
+
+        Ok(StreamData {
+            stream_id,
+            data: packet.slice(start..end),
+        })
+    }
+}
+
+impl Encode for StreamData {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.stream_id.encode(encoder)?;
+        self.data.as_ref().encode(encoder)
+    }
+}
+
+impl<Context> Decode<Context> for StreamData {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(StreamData {
+            stream_id: u32::decode(decoder)?,
+            data: Bytes::from(Vec::<u8>::decode(decoder)?),
+        })
+    }
+}
+
+impl<'de, Context> BorrowDecode<'de, Context> for StreamData {
+    fn borrow_decode<D: BorrowDecoder<'de, Context = Context>>(
+        decoder: &mut D,
+    ) -> Result<Self, DecodeError> {
+        Self::decode(decoder)
+    }
+}